@@ -0,0 +1,62 @@
+//! String-heavy workloads: equality, global access and field access.
+//!
+//! Run with `cargo bench --bench strings`.
+
+use std::time::{Duration, Instant};
+use rslox::VM;
+
+const RUNS: usize = 5;
+
+const EQUALITY: &str = r#"
+var a = "the quick brown fox jumps over the lazy dog, again and again and again";
+var b = "the quick brown fox jumps over the lazy dog, again and again and again";
+var same = 0;
+for (var i = 0; i < 200000; i = i + 1) {
+  if (a == b) same = same + 1;
+}
+"#;
+
+const GLOBALS: &str = r#"
+var counter = 0;
+var step = 1;
+for (var i = 0; i < 200000; i = i + 1) {
+  counter = counter + step;
+}
+"#;
+
+const FIELDS: &str = r#"
+class Point {
+  init(x, y) { this.x = x; this.y = y; }
+}
+var p = Point(1, 2);
+var sum = 0;
+for (var i = 0; i < 100000; i = i + 1) {
+  p.x = p.x + p.y;
+  sum = sum + p.x;
+}
+"#;
+
+fn bench(name: &str, source: &str) {
+    let mut best = Duration::MAX;
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let mut vm = VM::new();
+        let start = Instant::now();
+        vm.interpret(source).expect(name);
+        let elapsed = start.elapsed();
+        best = best.min(elapsed);
+        total += elapsed;
+    }
+    println!(
+        "{:<12} best {:>8.2?}  mean {:>8.2?}",
+        name,
+        best,
+        total / RUNS as u32
+    );
+}
+
+fn main() {
+    bench("equality", EQUALITY);
+    bench("globals", GLOBALS);
+    bench("fields", FIELDS);
+}
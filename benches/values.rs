@@ -0,0 +1,72 @@
+//! Stack-heavy and arithmetic-heavy workloads, for comparing the two `Value`
+//! representations:
+//!
+//! ```text
+//! cargo bench --bench values
+//! cargo bench --bench values --features nan-boxing
+//! ```
+
+use std::mem;
+use std::time::{Duration, Instant};
+use rslox::{Value, VM};
+
+const RUNS: usize = 5;
+
+/// Deep recursion and many live locals: mostly pushes, pops and calls.
+const STACK: &str = r#"
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+
+fun shuffle(a, b, c, d) {
+  var e = a; var f = b; var g = c; var h = d;
+  return h + g + f + e;
+}
+
+var result = fib(22);
+for (var i = 0; i < 50000; i = i + 1) {
+  result = shuffle(i, result, i, 1) - result - i - i;
+}
+"#;
+
+/// Tight numeric loops over locals.
+const ARITHMETIC: &str = r#"
+var result = 0;
+{
+  var x = 0;
+  var y = 1;
+  for (var i = 0; i < 300000; i = i + 1) {
+    x = x + i * 2 - y / 3;
+    y = (y + x) / 2 - i;
+    if (x > 1000000) x = x - 1000000;
+  }
+  result = x + y;
+}
+"#;
+
+fn bench(name: &str, source: &str) {
+    let mut best = Duration::MAX;
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let mut vm = VM::new();
+        let start = Instant::now();
+        vm.interpret(source).expect(name);
+        let elapsed = start.elapsed();
+        best = best.min(elapsed);
+        total += elapsed;
+    }
+    println!(
+        "{:<12} best {:>8.2?}  mean {:>8.2?}",
+        name,
+        best,
+        total / RUNS as u32
+    );
+}
+
+fn main() {
+    let repr = if cfg!(feature = "nan-boxing") { "nan-boxed" } else { "enum" };
+    println!("Value: {}, {} bytes", repr, mem::size_of::<Value>());
+    bench("stack", STACK);
+    bench("arithmetic", ARITHMETIC);
+}
@@ -1,18 +1,30 @@
+use crate::heap::{Heap, Obj};
 use crate::value::*;
-use std::convert::TryFrom;
 
-#[derive(Clone, Copy, Debug)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum OpCode {
     OpConstant,
+    OpConstantLong,
     OpNil,
     OpTrue,
     OpFalse,
     OpPop,
     OpDefineGlobal,
+    OpDefineGlobalLong,
     OpGetLocal,
+    OpGetLocalLong,
     OpSetLocal,
+    OpSetLocalLong,
     OpGetGlobal,
+    OpGetGlobalLong,
     OpSetGlobal,
+    OpSetGlobalLong,
+    OpGetUpvalue,
+    OpSetUpvalue,
+    OpGetProperty,
+    OpSetProperty,
+    OpGetSuper,
     OpEqual,
     OpGreater,
     OpLess,
@@ -24,39 +36,217 @@ pub enum OpCode {
     OpNegate,
     OpPrint,
     OpJumpIfFalse,
+    OpJumpIfFalseLong,
     OpJump,
+    OpJumpLong,
     OpLoop,
+    OpLoopLong,
+    OpCall,
+    OpClosure,
+    OpCloseUpvalue,
     OpReturn,
+    OpClass,
+    OpInherit,
+    OpMethod,
+    // Opcode numbers are part of the `.loxc` format: new opcodes are
+    // appended, and adding any bumps `serialize::VERSION`.
+    OpGetPropertyLong,
+    OpSetPropertyLong,
+    OpGetSuperLong,
+    OpClosureLong,
+    OpClassLong,
+    OpMethodLong,
 }
 
-impl From<u8> for OpCode {
-    fn from(code: u8) -> Self {
+impl TryFrom<u8> for OpCode {
+    /// The byte that does not name an opcode.
+    type Error = u8;
+
+    fn try_from(code: u8) -> Result<Self, u8> {
         match code {
-            0 => OpCode::OpConstant,
-            1 => OpCode::OpNil,
-            2 => OpCode::OpTrue,
-            3 => OpCode::OpFalse,
-            4 => OpCode::OpPop,
-            5 => OpCode::OpDefineGlobal,
-            6 => OpCode::OpGetLocal,
-            7 => OpCode::OpSetLocal,
-            8 => OpCode::OpGetGlobal,
-            9 => OpCode::OpSetGlobal,
-            10 => OpCode::OpEqual,
-            11 => OpCode::OpGreater,
-            12 => OpCode::OpLess,
-            13 => OpCode::OpAdd,
-            14 => OpCode::OpSubtract,
-            15 => OpCode::OpMultiply,
-            16 => OpCode::OpDivide,
-            17 => OpCode::OpNot,
-            18 => OpCode::OpNegate,
-            19 => OpCode::OpPrint,
-            20 => OpCode::OpJumpIfFalse,
-            21 => OpCode::OpJump,
-            22 => OpCode::OpLoop,
-            23 => OpCode::OpReturn,
-            _ => unimplemented!("Invalid opcode {}", code),
+            0 => Ok(OpCode::OpConstant),
+            1 => Ok(OpCode::OpConstantLong),
+            2 => Ok(OpCode::OpNil),
+            3 => Ok(OpCode::OpTrue),
+            4 => Ok(OpCode::OpFalse),
+            5 => Ok(OpCode::OpPop),
+            6 => Ok(OpCode::OpDefineGlobal),
+            7 => Ok(OpCode::OpDefineGlobalLong),
+            8 => Ok(OpCode::OpGetLocal),
+            9 => Ok(OpCode::OpGetLocalLong),
+            10 => Ok(OpCode::OpSetLocal),
+            11 => Ok(OpCode::OpSetLocalLong),
+            12 => Ok(OpCode::OpGetGlobal),
+            13 => Ok(OpCode::OpGetGlobalLong),
+            14 => Ok(OpCode::OpSetGlobal),
+            15 => Ok(OpCode::OpSetGlobalLong),
+            16 => Ok(OpCode::OpGetUpvalue),
+            17 => Ok(OpCode::OpSetUpvalue),
+            18 => Ok(OpCode::OpGetProperty),
+            19 => Ok(OpCode::OpSetProperty),
+            20 => Ok(OpCode::OpGetSuper),
+            21 => Ok(OpCode::OpEqual),
+            22 => Ok(OpCode::OpGreater),
+            23 => Ok(OpCode::OpLess),
+            24 => Ok(OpCode::OpAdd),
+            25 => Ok(OpCode::OpSubtract),
+            26 => Ok(OpCode::OpMultiply),
+            27 => Ok(OpCode::OpDivide),
+            28 => Ok(OpCode::OpNot),
+            29 => Ok(OpCode::OpNegate),
+            30 => Ok(OpCode::OpPrint),
+            31 => Ok(OpCode::OpJumpIfFalse),
+            32 => Ok(OpCode::OpJumpIfFalseLong),
+            33 => Ok(OpCode::OpJump),
+            34 => Ok(OpCode::OpJumpLong),
+            35 => Ok(OpCode::OpLoop),
+            36 => Ok(OpCode::OpLoopLong),
+            37 => Ok(OpCode::OpCall),
+            38 => Ok(OpCode::OpClosure),
+            39 => Ok(OpCode::OpCloseUpvalue),
+            40 => Ok(OpCode::OpReturn),
+            41 => Ok(OpCode::OpClass),
+            42 => Ok(OpCode::OpInherit),
+            43 => Ok(OpCode::OpMethod),
+            44 => Ok(OpCode::OpGetPropertyLong),
+            45 => Ok(OpCode::OpSetPropertyLong),
+            46 => Ok(OpCode::OpGetSuperLong),
+            47 => Ok(OpCode::OpClosureLong),
+            48 => Ok(OpCode::OpClassLong),
+            49 => Ok(OpCode::OpMethodLong),
+            _ => Err(code),
+        }
+    }
+}
+
+/// Largest operand the long instruction forms can encode.
+pub const MAX_LONG_OPERAND: usize = (1 << 24) - 1;
+
+impl OpCode {
+    /// The variant of the instruction taking a 24-bit operand, if any.
+    pub fn long_form(self) -> Option<OpCode> {
+        match self {
+            OpCode::OpConstant => Some(OpCode::OpConstantLong),
+            OpCode::OpDefineGlobal => Some(OpCode::OpDefineGlobalLong),
+            OpCode::OpGetLocal => Some(OpCode::OpGetLocalLong),
+            OpCode::OpSetLocal => Some(OpCode::OpSetLocalLong),
+            OpCode::OpGetGlobal => Some(OpCode::OpGetGlobalLong),
+            OpCode::OpSetGlobal => Some(OpCode::OpSetGlobalLong),
+            OpCode::OpJumpIfFalse => Some(OpCode::OpJumpIfFalseLong),
+            OpCode::OpJump => Some(OpCode::OpJumpLong),
+            OpCode::OpLoop => Some(OpCode::OpLoopLong),
+            OpCode::OpGetProperty => Some(OpCode::OpGetPropertyLong),
+            OpCode::OpSetProperty => Some(OpCode::OpSetPropertyLong),
+            OpCode::OpGetSuper => Some(OpCode::OpGetSuperLong),
+            OpCode::OpClosure => Some(OpCode::OpClosureLong),
+            OpCode::OpClass => Some(OpCode::OpClassLong),
+            OpCode::OpMethod => Some(OpCode::OpMethodLong),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction is the 24-bit form of another.
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::OpConstantLong
+                | OpCode::OpDefineGlobalLong
+                | OpCode::OpGetLocalLong
+                | OpCode::OpSetLocalLong
+                | OpCode::OpGetGlobalLong
+                | OpCode::OpSetGlobalLong
+                | OpCode::OpJumpIfFalseLong
+                | OpCode::OpJumpLong
+                | OpCode::OpLoopLong
+                | OpCode::OpGetPropertyLong
+                | OpCode::OpSetPropertyLong
+                | OpCode::OpGetSuperLong
+                | OpCode::OpClosureLong
+                | OpCode::OpClassLong
+                | OpCode::OpMethodLong
+        )
+    }
+}
+
+impl OpCode {
+    /// The instruction's name as the disassembler prints it.
+    pub fn name(self) -> &'static str {
+        match self {
+            OpCode::OpConstant => "OP_CONSTANT",
+            OpCode::OpConstantLong => "OP_CONSTANT_LONG",
+            OpCode::OpNil => "OP_NIL",
+            OpCode::OpTrue => "OP_TRUE",
+            OpCode::OpFalse => "OP_FALSE",
+            OpCode::OpPop => "OP_POP",
+            OpCode::OpDefineGlobal => "OP_DEFINE_GLOBAL",
+            OpCode::OpDefineGlobalLong => "OP_DEFINE_GLOBAL_LONG",
+            OpCode::OpGetLocal => "OP_GET_LOCAL",
+            OpCode::OpGetLocalLong => "OP_GET_LOCAL_LONG",
+            OpCode::OpSetLocal => "OP_SET_LOCAL",
+            OpCode::OpSetLocalLong => "OP_SET_LOCAL_LONG",
+            OpCode::OpGetGlobal => "OP_GET_GLOBAL",
+            OpCode::OpGetGlobalLong => "OP_GET_GLOBAL_LONG",
+            OpCode::OpSetGlobal => "OP_SET_GLOBAL",
+            OpCode::OpSetGlobalLong => "OP_SET_GLOBAL_LONG",
+            OpCode::OpGetUpvalue => "OP_GET_UPVALUE",
+            OpCode::OpSetUpvalue => "OP_SET_UPVALUE",
+            OpCode::OpGetProperty => "OP_GET_PROPERTY",
+            OpCode::OpSetProperty => "OP_SET_PROPERTY",
+            OpCode::OpGetSuper => "OP_GET_SUPER",
+            OpCode::OpEqual => "OP_EQUAL",
+            OpCode::OpGreater => "OP_GREATER",
+            OpCode::OpLess => "OP_LESS",
+            OpCode::OpAdd => "OP_ADD",
+            OpCode::OpSubtract => "OP_SUBTRACT",
+            OpCode::OpMultiply => "OP_MULTIPLY",
+            OpCode::OpDivide => "OP_DIVIDE",
+            OpCode::OpNot => "OP_NOT",
+            OpCode::OpNegate => "OP_NEGATE",
+            OpCode::OpPrint => "OP_PRINT",
+            OpCode::OpJumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::OpJumpIfFalseLong => "OP_JUMP_IF_FALSE_LONG",
+            OpCode::OpJump => "OP_JUMP",
+            OpCode::OpJumpLong => "OP_JUMP_LONG",
+            OpCode::OpLoop => "OP_LOOP",
+            OpCode::OpLoopLong => "OP_LOOP_LONG",
+            OpCode::OpCall => "OP_CALL",
+            OpCode::OpClosure => "OP_CLOSURE",
+            OpCode::OpCloseUpvalue => "OP_CLOSE_UPVALUE",
+            OpCode::OpReturn => "OP_RETURN",
+            OpCode::OpClass => "OP_CLASS",
+            OpCode::OpInherit => "OP_INHERIT",
+            OpCode::OpMethod => "OP_METHOD",
+            OpCode::OpGetPropertyLong => "OP_GET_PROPERTY_LONG",
+            OpCode::OpSetPropertyLong => "OP_SET_PROPERTY_LONG",
+            OpCode::OpGetSuperLong => "OP_GET_SUPER_LONG",
+            OpCode::OpClosureLong => "OP_CLOSURE_LONG",
+            OpCode::OpClassLong => "OP_CLASS_LONG",
+            OpCode::OpMethodLong => "OP_METHOD_LONG",
+        }
+    }
+
+    /// Number of operand bytes following the opcode. Closures are further
+    /// followed by its captured variables; see [`Chunk::instruction_len`].
+    pub fn operand_len(self) -> usize {
+        match self {
+            _ if self.is_long() => 3,
+            OpCode::OpJumpIfFalse | OpCode::OpJump | OpCode::OpLoop => 2,
+            OpCode::OpConstant
+            | OpCode::OpDefineGlobal
+            | OpCode::OpGetLocal
+            | OpCode::OpSetLocal
+            | OpCode::OpGetGlobal
+            | OpCode::OpSetGlobal
+            | OpCode::OpGetUpvalue
+            | OpCode::OpSetUpvalue
+            | OpCode::OpGetProperty
+            | OpCode::OpSetProperty
+            | OpCode::OpGetSuper
+            | OpCode::OpCall
+            | OpCode::OpClosure
+            | OpCode::OpClass
+            | OpCode::OpMethod => 1,
+            _ => 0,
         }
     }
 }
@@ -74,6 +264,12 @@ pub struct Chunk {
     pub lines: Vec<usize>,
 }
 
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
@@ -93,97 +289,65 @@ impl Chunk {
         self.lines.push(line);
     }
 
-    pub fn read_byte(&self, offset: usize) -> u8 {
-        self.code[offset].into()
+    /// Writes a 24-bit operand, most significant byte first.
+    pub fn write_u24(&mut self, operand: usize, line: usize) {
+        self.write_u8((operand >> 16) as u8, line);
+        self.write_u8((operand >> 8) as u8, line);
+        self.write_u8(operand as u8, line);
     }
 
-    pub fn add_constant(&mut self, val: Value) -> Option<u8> {
-        self.constants.push(val);
-        u8::try_from(self.constants.len() - 1).ok()
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        self.code[offset]
     }
 
-    pub fn get_constant(&self, idx: usize) -> &Value {
-        self.constants.get(idx).expect("None Value!!!")
+    /// Decodes the opcode at `offset`, which must start an instruction of
+    /// compiled or [verified](crate::verify) code.
+    pub fn opcode(&self, offset: usize) -> OpCode {
+        OpCode::try_from(self.code[offset]).expect("Invalid opcode in unverified chunk")
     }
 
-    #[allow(dead_code)]
-    pub fn disassemble_chunk<T: ToString>(&self, name: T) {
-        println!("== {} ==", name.to_string());
-
-        let mut idx = 0;
-        while idx < self.code.len() {
-            idx = self.disassemble_instruction(idx);
-        }
+    pub fn read_u24(&self, offset: usize) -> usize {
+        (self.code[offset] as usize) << 16
+            | (self.code[offset + 1] as usize) << 8
+            | self.code[offset + 2] as usize
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-
-        print!("{:04} ", offset);
-
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("{:>4} ", "|"); // right justify
+    /// Reads the index operand of the instruction at `offset`, which is one
+    /// byte wide or three for the long forms. Returns the operand and the
+    /// offset of the next instruction.
+    pub fn read_index(&self, offset: usize) -> (usize, usize) {
+        if self.opcode(offset).is_long() {
+            (self.read_u24(offset + 1), offset + 4)
         } else {
-            print!("{:4} ", self.lines[offset]);
+            (self.code[offset + 1] as usize, offset + 2)
         }
-
-        let instruction = &self.code[offset].into();
-
-        match instruction {
-            OpCode::OpConstant => self.constant_instruction("OP_CONSTANT", offset),
-            OpCode::OpNil => self.simple_instruction("OP_NIL", offset),
-            OpCode::OpTrue => self.simple_instruction("OP_TRUE", offset),
-            OpCode::OpFalse => self.simple_instruction("OP_FALSE", offset),
-            OpCode::OpPop => self.simple_instruction("OP_POP", offset),
-            OpCode::OpDefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", offset),
-            OpCode::OpGetLocal => self.byte_instruction("OP_GET_LOCAL", offset),
-            OpCode::OpSetLocal => self.byte_instruction("OP_SET_LOCAL", offset),
-            OpCode::OpGetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset),
-            OpCode::OpSetGlobal => self.constant_instruction("OP_SET_GLOBAL", offset),
-            OpCode::OpEqual => self.simple_instruction("OP_EQUAL", offset),
-            OpCode::OpGreater => self.simple_instruction("OP_GREATER", offset),
-            OpCode::OpLess => self.simple_instruction("OP_LESS", offset),
-            OpCode::OpAdd => self.simple_instruction("OP_ADD", offset),
-            OpCode::OpSubtract => self.simple_instruction("OP_SUBTRACT", offset),
-            OpCode::OpMultiply => self.simple_instruction("OP_MULTIPLY", offset),
-            OpCode::OpDivide => self.simple_instruction("OP_DIVIDE", offset),
-            OpCode::OpNot => self.simple_instruction("OP_NOT", offset),
-            OpCode::OpNegate => self.simple_instruction("OP_NEGATE", offset),
-            OpCode::OpPrint => self.simple_instruction("OP_PRINT", offset),
-            OpCode::OpJumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE", 1, offset),
-            OpCode::OpJump => self.jump_instruction("OP_JUMP", 1, offset),
-            OpCode::OpLoop => self.jump_instruction("OP_LOOP", -1, offset),
-            OpCode::OpReturn => self.simple_instruction("OP_RETURN", offset),
-        }
-
-    }
-
-    pub fn simple_instruction(&self, name: &str, idx: usize) -> usize {
-        println!("{}", name);
-        idx + 1
     }
 
-    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant_idx: u8 = self.code[offset + 1].into();
-        print!("{:-16}{:4} '", name, &constant_idx);
-        offset + 2
+    /// Length in bytes of the instruction at `offset`, operands included.
+    /// Closures are variable-length, so their function is looked up in `heap`.
+    pub fn instruction_len(&self, offset: usize, heap: &Heap) -> usize {
+        let op = self.opcode(offset);
+        let mut len = 1 + op.operand_len();
+        if matches!(op, OpCode::OpClosure | OpCode::OpClosureLong) {
+            let constant = self.constants[self.read_index(offset).0];
+            let upvalue_count = match constant.as_obj().map(|r| heap.get(r)) {
+                Some(Obj::Function(function)) => function.upvalue_count,
+                _ => 0,
+            };
+            for _ in 0..upvalue_count {
+                let wide = self.code[offset + len] & 2 != 0;
+                len += if wide { 4 } else { 2 };
+            }
+        }
+        len
     }
 
-    fn jump_instruction(&self, name: &str, sign: i16, offset: usize) -> usize {
-        let jump = (((self.code[offset + 1] as u16) << 8) | self.code[offset + 2] as u16) as usize;
-        let jump_to = if sign == 1 {
-            offset + 3 + jump
-        } else {
-            offset + 3 - jump
-        };
-        println!("{:-16} {:4} -> {}", name, offset, jump_to);
-        offset + 3
+    pub fn add_constant(&mut self, val: Value) -> usize {
+        self.constants.push(val);
+        self.constants.len() - 1
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant_idx: u8 = self.code[offset + 1].into();
-        print!("{:-16}{:4} '", name, &constant_idx);
-        print!("{}", self.constants[constant_idx as usize]);
-        println!("'");
-        offset + 2
+    pub fn get_constant(&self, idx: usize) -> Option<Value> {
+        self.constants.get(idx).copied()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::heap::ObjRef;
+use crate::value::Value;
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    /// Keyed by interned method name.
+    pub methods: HashMap<ObjRef, ObjRef>,
+}
+
+impl Class {
+    pub fn new(name: String) -> Self {
+        Class {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    /// Keyed by interned field name.
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+impl Instance {
+    pub fn new(class: ObjRef) -> Self {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+/// A method closure paired with the instance it was accessed from, so that
+/// `this` still refers to that instance when the method is called later.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: ObjRef) -> Self {
+        BoundMethod { receiver, method }
+    }
+}
@@ -1,7 +1,10 @@
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::{Chunk, OpCode, MAX_LONG_OPERAND};
 use crate::scanner::{Scanner, Token, TokenType};
 use crate::value::Value;
 use crate::function::Function;
+use crate::error::{Diagnostic, ErrorKind};
+use crate::heap::{Obj, ObjRef};
+use crate::vm::VM;
 
 use std::collections::HashMap;
 
@@ -66,53 +69,145 @@ impl<'src> ParseRule<'src> {
 pub struct Local<'src> {
     name: Token<'src>,
     depth: i32,
+    is_captured: bool,
 }
 
 impl<'src> Local<'src> {
     pub fn new(name: Token<'src>, depth: i32 ) -> Local<'src> {
-        Local {name, depth}
+        Local {name, depth, is_captured: false}
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub struct Upvalue {
+    index: usize,
+    is_local: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
 enum FunctionType {
     Function,
+    Initializer,
+    Method,
     Script,
 }
 
 pub struct Compiler<'src> {
+    enclosing: Option<Box<Compiler<'src>>>,
     pub function: Function,
     fn_type: FunctionType,
     locals: Vec<Local<'src>>,
+    upvalues: Vec<Upvalue>,
     scope_depth: i32,
 }
 
 impl<'src> Compiler<'src> {
-    pub fn new() -> Compiler<'src> {
+    fn new(fn_type: FunctionType) -> Compiler<'src> {
+        let mut locals = Vec::with_capacity(USIZE_COUNT);
+        // Slot zero holds the function being called, or the receiver in methods.
+        let slot_zero = match fn_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local::new(Token::new(TokenType::Eof, 0, slot_zero), 0));
+
         Compiler {
+            enclosing: None,
             function: Function::new(),
-            fn_type: FunctionType::Script,
-            locals: Vec::with_capacity(USIZE_COUNT),
+            fn_type,
+            locals,
+            upvalues: Vec::new(),
             scope_depth: 0,
         }
     }
+
+    /// Returns the slot of the named local and whether it has been initialized.
+    fn resolve_local(&self, name: &Token) -> Option<(usize, bool)> {
+        self.locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.lexeme == name.lexeme)
+            .map(|(i, local)| (i, local.depth != -1))
+    }
+
+    fn add_upvalue(&mut self, index: usize, is_local: bool) -> Result<u8, &'static str> {
+        let upvalue = Upvalue { index, is_local };
+        if let Some(i) = self.upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(i as u8);
+        }
+
+        if self.upvalues.len() == USIZE_COUNT {
+            return Err("Too many closure variables in function.");
+        }
+
+        self.upvalues.push(upvalue);
+        self.function.upvalue_count = self.upvalues.len();
+        Ok((self.upvalues.len() - 1) as u8)
+    }
+
+    /// Looks the name up in the enclosing compilers, threading an upvalue
+    /// through every function in between.
+    fn resolve_upvalue(&mut self, name: &Token) -> Result<Option<u8>, &'static str> {
+        let enclosing = match self.enclosing.as_mut() {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+
+        if let Some((local, initialized)) = enclosing.resolve_local(name) {
+            if !initialized {
+                return Err("Can't read local variable in its own initializer.");
+            }
+            enclosing.locals[local].is_captured = true;
+            return self.add_upvalue(local, true).map(Some);
+        }
+
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(upvalue as usize, false).map(Some);
+        }
+
+        Ok(None)
+    }
+}
+
+pub struct ClassCompiler {
+    has_superclass: bool,
 }
 
 pub struct Parser<'src> {
+    vm: &'src mut VM,
     scanner: Scanner<'src>,
+    source: &'src str,
     pub compiler: Compiler<'src>,
+    class_compilers: Vec<ClassCompiler>,
     current: Token<'src>,
     previous: Token<'src>,
     rules: HashMap<TokenType, ParseRule<'src>>,
-    had_error: bool,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
+    /// Emit forward jumps in their 24-bit form. Set when a first attempt
+    /// found a jump too long for 16 bits.
+    wide_jumps: bool,
+    jump_overflow: bool,
+    /// Return the value of a trailing top-level expression statement from
+    /// the script, for the REPL to echo.
+    echo: bool,
+    /// Offset of the `OpPop` ending the last top-level expression statement,
+    /// while it is still the most recent declaration.
+    trailing_expression: Option<usize>,
+    /// Offset of the current top-level declaration's first instruction,
+    /// which tells its own expression statement from one nested in it.
+    declaration_start: usize,
+    echoed: bool,
 }
 
 impl<'src> Parser<'src> {
-    pub fn new(src: &'src str) -> Parser<'src> {
+    /// Creates a parser for `src` that allocates its constants in `vm`'s heap.
+    pub fn new(src: &'src str, vm: &'src mut VM) -> Parser<'src> {
         let mut rule_map = HashMap::new();
         rule_map.insert(
             TokenType::LeftParen,
-            ParseRule::new(Some(Parser::grouping), None, Precedence::None),
+            ParseRule::new(Some(Parser::grouping), Some(Parser::call), Precedence::Call),
         );
         rule_map.insert(
             TokenType::RightParen,
@@ -130,7 +225,7 @@ impl<'src> Parser<'src> {
             TokenType::Comma,
             ParseRule::new(None, None, Precedence::None),
         );
-        rule_map.insert(TokenType::Dot, ParseRule::new(None, None, Precedence::None));
+        rule_map.insert(TokenType::Dot, ParseRule::new(None, Some(Parser::dot), Precedence::Call));
         rule_map.insert(
             TokenType::Minus,
             ParseRule::new(Some(Parser::unary), Some(Parser::binary), Precedence::Term),
@@ -223,11 +318,11 @@ impl<'src> Parser<'src> {
         );
         rule_map.insert(
             TokenType::Super,
-            ParseRule::new(None, None, Precedence::None),
+            ParseRule::new(Some(Parser::super_), None, Precedence::None),
         );
         rule_map.insert(
             TokenType::This,
-            ParseRule::new(None, None, Precedence::None),
+            ParseRule::new(Some(Parser::this), None, Precedence::None),
         );
         rule_map.insert(
             TokenType::True,
@@ -247,27 +342,81 @@ impl<'src> Parser<'src> {
         let dummy_token = Token::new(TokenType::Eof, 0, "");
         let dummy_token2 = Token::new(TokenType::Eof, 0, "");
         Parser {
-            compiler: Compiler::new(),
+            vm,
+            source: src,
+            compiler: Compiler::new(FunctionType::Script),
+            class_compilers: Vec::new(),
             current: dummy_token,
             previous: dummy_token2,
             scanner: Scanner::new(src),
             rules: rule_map,
-            had_error: false,
+            diagnostics: Vec::new(),
             panic_mode: false,
+            wide_jumps: false,
+            jump_overflow: false,
+            echo: false,
+            trailing_expression: None,
+            declaration_start: 0,
+            echoed: false,
+        }
+    }
+
+    pub fn compile(mut self) -> Result<Function, Vec<Diagnostic>> {
+        let compiler = self.compile_script();
+        if self.had_error() {
+            Err(self.diagnostics)
+        } else {
+            Ok(compiler.function)
+        }
+    }
+
+    /// Compiles one REPL entry. If the source ends with an expression
+    /// statement, the script returns that expression's value rather than
+    /// nil; the flag says whether it does.
+    pub fn compile_repl(mut self) -> Result<(Function, bool), Vec<Diagnostic>> {
+        self.echo = true;
+        let compiler = self.compile_script();
+        if self.had_error() {
+            Err(self.diagnostics)
+        } else {
+            Ok((compiler.function, self.echoed))
+        }
+    }
+
+    fn compile_script(&mut self) -> Compiler<'src> {
+        let compiler = self.script();
+        if !self.jump_overflow {
+            return compiler;
         }
+        // A forward jump's target is only known once it has been emitted
+        // with a 16-bit operand. Rather than shift code that later jumps
+        // already point past, start over with every forward jump wide.
+        self.restart_with_wide_jumps();
+        self.script()
     }
 
-    pub fn compile(&mut self) -> Option<&Function> {
+    fn script(&mut self) -> Compiler<'src> {
         self.advance();
         while !self.match_type(TokenType::Eof) {
+            self.trailing_expression = None;
+            self.declaration_start = self.current_chunk().code.len();
             self.declaration();
         }
-        
-        if self.had_error {
-            None
-        } else {
-            self.end_compiler()
-        }
+        self.end_compiler()
+    }
+
+    fn restart_with_wide_jumps(&mut self) {
+        self.scanner = Scanner::new(self.source);
+        self.compiler = Compiler::new(FunctionType::Script);
+        self.class_compilers.clear();
+        self.current = Token::new(TokenType::Eof, 0, "");
+        self.previous = Token::new(TokenType::Eof, 0, "");
+        self.diagnostics.clear();
+        self.panic_mode = false;
+        self.wide_jumps = true;
+        self.jump_overflow = false;
+        self.trailing_expression = None;
+        self.echoed = false;
     }
 
     fn advance(&mut self) {
@@ -316,74 +465,130 @@ impl<'src> Parser<'src> {
 
     fn emit_bytes(&mut self, byte1: OpCode, byte2: u8) {
         self.emit_byte(byte1);
-        self.emit_byte(byte2.into());
+        self.emit_u8(byte2);
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
-        self.emit_byte(OpCode::OpLoop);
-
-        let offset = self.current_chunk().code.len() - loop_start + 2;
-        if offset > u16::MAX.into() { self.error("Loop body too large.")}
+        // +3 to jump back over the OpLoop instruction itself.
+        let offset = self.current_chunk().code.len() - loop_start + 3;
+        if offset <= u16::MAX.into() {
+            self.emit_byte(OpCode::OpLoop);
+            self.emit_u8(((offset >> 8) & 0xff) as u8);
+            self.emit_u8((offset & 0xff) as u8);
+            return;
+        }
 
-        self.emit_u8(((offset >> 8) & 0xff) as u8);
-        self.emit_u8((offset & 0xff) as u8);
+        let offset = offset + 1;
+        if offset > MAX_LONG_OPERAND {
+            self.error("Loop body too large.");
+        }
+        self.emit_byte(OpCode::OpLoopLong);
+        let line = self.previous.line;
+        self.current_chunk().write_u24(offset, line);
     }
 
     fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        if self.wide_jumps {
+            let instruction = instruction.long_form().expect("Jump without long form");
+            self.emit_byte(instruction);
+            let line = self.previous.line;
+            self.current_chunk().write_u24(MAX_LONG_OPERAND, line);
+            return self.current_chunk().code.len() - 3;
+        }
+
         self.emit_byte(instruction);
         self.emit_u8(0xff);
         self.emit_u8(0xff);
-        return self.current_chunk().code.len() - 2;
+        self.current_chunk().code.len() - 2
     }
 
     fn emit_return(&mut self) {
+        if let Some(pop) = self.trailing_expression.take() {
+            // Return the expression's value instead of discarding it, as
+            // long as nothing was emitted after its pop.
+            let chunk = self.current_chunk();
+            if pop + 1 == chunk.code.len() {
+                chunk.code.pop();
+                let line = chunk.lines.pop().expect("Pop without a line");
+                chunk.write_byte(OpCode::OpReturn, line);
+                self.echoed = true;
+                return;
+            }
+        }
+
+        if self.compiler.fn_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::OpGetLocal, 0);
+        } else {
+            self.emit_byte(OpCode::OpNil);
+        }
         self.emit_byte(OpCode::OpReturn);
     }
 
-    fn make_constant(&mut self, val: Value) -> u8 {
-        if let Some(constant) = self.current_chunk().add_constant(val) {
-            constant
-        } else {
+    fn make_constant(&mut self, val: Value) -> usize {
+        let constant = self.current_chunk().add_constant(val);
+        if constant > MAX_LONG_OPERAND {
             self.error("Too many constants in one chunk.");
-            0
+            return 0;
         }
+        constant
     }
 
     fn emit_constant(&mut self, val: Value) {
         let con_idx = self.make_constant(val);
-        self.emit_bytes(OpCode::OpConstant, con_idx);
+        self.emit_indexed(OpCode::OpConstant, con_idx);
+    }
+
+    /// Emits `op` with an index operand, switching to the instruction's long
+    /// form when the index does not fit in a byte.
+    fn emit_indexed(&mut self, op: OpCode, index: usize) {
+        match (u8::try_from(index), op.long_form()) {
+            (Ok(byte), _) => self.emit_bytes(op, byte),
+            (Err(_), Some(long)) => {
+                self.emit_byte(long);
+                let line = self.previous.line;
+                self.current_chunk().write_u24(index, line);
+            }
+            (Err(_), None) => unreachable!("{:?} has no long form", op),
+        }
     }
 
     fn patch_jump(&mut self, offset: usize) {
+        let instruction = self.current_chunk().opcode(offset - 1);
+        if instruction.is_long() {
+            // -3 to adjust for the bytecode for the jump offset itself.
+            let jump = self.current_chunk().code.len() - offset - 3;
+            if jump > MAX_LONG_OPERAND {
+                self.error("Too much code to jump over.");
+            }
+
+            let code = &mut self.current_chunk().code;
+            code[offset] = ((jump >> 16) & 0xff) as u8;
+            code[offset + 1] = ((jump >> 8) & 0xff) as u8;
+            code[offset + 2] = (jump & 0xff) as u8;
+            return;
+        }
+
         // -2 to adjust for the bytecode for the jump offset itself.
         let jump = self.current_chunk().code.len() - offset - 2;
-
-        if jump > USIZE_COUNT {
-            self.error("Too much code to jump over.");
+        if jump > u16::MAX.into() {
+            // compile() starts over with wide jumps.
+            self.jump_overflow = true;
+            return;
         }
 
-        self.current_chunk().code[offset] = (((jump >> 8) & 0xff) as u8).into();
-        self.current_chunk().code[offset + 1] = ((jump & 0xff) as u8).into();
+        self.current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
+        self.current_chunk().code[offset + 1] = (jump & 0xff) as u8;
     }
 
-    fn end_compiler(&mut self) -> Option<&Function> {
+    fn end_compiler(&mut self) -> Compiler<'src> {
         self.emit_return();
 
-        #[cfg(debug_assertions)]
-        if !self.had_error {
-            let name = if self.compiler.function.name != "" {
-                self.compiler.function.name.clone()
-            } else {
-                String::from("<script>")
-            };
-            self.current_chunk().disassemble_chunk(name);
-        }
-
-        if self.had_error {
-            None
-        } else {
-            Some(&self.compiler.function)
-        }
+        // Hand control back to the enclosing compiler, if any.
+        let enclosing = match self.compiler.enclosing.take() {
+            Some(enclosing) => *enclosing,
+            None => Compiler::new(FunctionType::Script),
+        };
+        std::mem::replace(&mut self.compiler, enclosing)
     }
 
     fn begin_scope(&mut self) {
@@ -393,35 +598,56 @@ impl<'src> Parser<'src> {
     fn end_scope(&mut self) {
         self.compiler.scope_depth -= 1;
 
-        while self.compiler.locals.len() > 0 &&
+        while !self.compiler.locals.is_empty() &&
             self.compiler.locals[self.compiler.locals.len() - 1].depth > self.compiler.scope_depth
         {
-            self.emit_byte(OpCode::OpPop);
-            self.compiler.locals.pop();
+            let local = self.compiler.locals.pop().unwrap();
+            if local.is_captured {
+                self.emit_byte(OpCode::OpCloseUpvalue);
+            } else {
+                self.emit_byte(OpCode::OpPop);
+            }
         }
     }
 
-    fn binary(&mut self, can_assign: bool) {
+    fn binary(&mut self, _can_assign: bool) {
         let op_type = self.previous.token_type;
         let rule = self.get_rule(op_type);
         self.parse_precedence(rule.precedence.next());
 
         match op_type {
-            TokenType::Plus  => self.emit_byte(OpCode::OpAdd.into()),
-            TokenType::Minus => self.emit_byte(OpCode::OpSubtract.into()),
-            TokenType::Star  => self.emit_byte(OpCode::OpMultiply.into()),
-            TokenType::Slash => self.emit_byte(OpCode::OpDivide.into()),
+            TokenType::Plus  => self.emit_byte(OpCode::OpAdd),
+            TokenType::Minus => self.emit_byte(OpCode::OpSubtract),
+            TokenType::Star  => self.emit_byte(OpCode::OpMultiply),
+            TokenType::Slash => self.emit_byte(OpCode::OpDivide),
             TokenType::BangEqual => self.emit_bytes(OpCode::OpEqual, OpCode::OpNot.into()),
-            TokenType::EqualEqual => self.emit_byte(OpCode::OpEqual.into()),
-            TokenType::Greater => self.emit_byte(OpCode::OpGreater.into()),
+            TokenType::EqualEqual => self.emit_byte(OpCode::OpEqual),
+            TokenType::Greater => self.emit_byte(OpCode::OpGreater),
             TokenType::GreaterEqual => self.emit_bytes(OpCode::OpLess, OpCode::OpNot.into()),
-            TokenType::Less => self.emit_byte(OpCode::OpLess.into()),
+            TokenType::Less => self.emit_byte(OpCode::OpLess),
             TokenType::LessEqual => self.emit_bytes(OpCode::OpGreater, OpCode::OpNot.into()),
             _ => ()   // Unreachable.
         }
     }
 
-    fn literal(&mut self, can_assign: bool) {
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::OpCall, arg_count);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.previous);
+
+        if can_assign && self.match_type(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed(OpCode::OpSetProperty, name);
+        } else {
+            self.emit_indexed(OpCode::OpGetProperty, name);
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
         match self.previous.token_type {
             TokenType::False => self.emit_byte(OpCode::OpFalse),
             TokenType::Nil   => self.emit_byte(OpCode::OpNil),
@@ -430,17 +656,17 @@ impl<'src> Parser<'src> {
         }
     }
 
-    fn grouping(&mut self, can_assign: bool) {
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
-    fn number(&mut self, can_assign: bool) {
+    fn number(&mut self, _can_assign: bool) {
         let val = self.previous.lexeme.parse().expect("Cannot convert str to f64");
-        self.emit_constant(Value::Number(val));
+        self.emit_constant(Value::number(val));
     }
 
-    fn or(&mut self, can_assign: bool) {
+    fn or(&mut self, _can_assign: bool) {
         let else_jump = self.emit_jump(OpCode::OpJumpIfFalse);
         let end_jump = self.emit_jump(OpCode::OpJump);
 
@@ -451,18 +677,20 @@ impl<'src> Parser<'src> {
         self.patch_jump(end_jump);
     }
 
-    fn string(&mut self, can_assign: bool) {
-        let len = self.previous.lexeme.len() - 1;
-        let st = String::from(&self.previous.lexeme[1..len]);
-        self.emit_constant(Value::ObjString(st));
+    fn string(&mut self, _can_assign: bool) {
+        let lexeme = self.previous.lexeme;
+        let r = self.intern(&lexeme[1..lexeme.len() - 1]);
+        self.emit_constant(Value::obj(r));
     }
 
     fn named_variable(&mut self, name: &Token, can_assign: bool) {
         let (arg, get_op, set_op) = if let Some(local_arg) = self.resolve_local(*name) {
             (local_arg, OpCode::OpGetLocal, OpCode::OpSetLocal)
+        } else if let Some(upvalue_arg) = self.resolve_upvalue(*name) {
+            (upvalue_arg as usize, OpCode::OpGetUpvalue, OpCode::OpSetUpvalue)
         } else {
             (
-                self.identifier_constant(*name),
+                self.global_slot(*name),
                 OpCode::OpGetGlobal,
                 OpCode::OpSetGlobal,
             )
@@ -470,9 +698,9 @@ impl<'src> Parser<'src> {
 
         if can_assign && self.match_type(TokenType::Equal) {
             self.expression();
-            self.emit_bytes(set_op, arg as u8);
+            self.emit_indexed(set_op, arg);
         } else {
-            self.emit_bytes(get_op,arg as u8);
+            self.emit_indexed(get_op, arg);
         }
     }
 
@@ -480,7 +708,39 @@ impl<'src> Parser<'src> {
         self.named_variable(&self.previous.clone(), can_assign);
     }
 
-    fn unary(&mut self, can_assign: bool) {
+    fn super_(&mut self, _can_assign: bool) {
+        let error = match self.class_compilers.last() {
+            None => Some("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                Some("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => None,
+        };
+        if let Some(message) = error {
+            // Resolving `super` now would declare it as a global.
+            self.error(message);
+            return;
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.previous);
+
+        let line = self.previous.line;
+        self.named_variable(&Token::new(TokenType::This, line, "this"), false);
+        self.named_variable(&Token::new(TokenType::Super, line, "super"), false);
+        self.emit_indexed(OpCode::OpGetSuper, name);
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.class_compilers.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        self.variable(false);
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
         let op_type = self.previous.token_type;
 
         // Compile the operand.
@@ -488,8 +748,8 @@ impl<'src> Parser<'src> {
 
         // Emit the operator instruction.
         match op_type {
-            TokenType::Minus => self.emit_byte(OpCode::OpNegate.into()),
-            TokenType::Bang  => self.emit_byte(OpCode::OpNot.into()),
+            TokenType::Minus => self.emit_byte(OpCode::OpNegate),
+            TokenType::Bang  => self.emit_byte(OpCode::OpNot),
             _ => ()
         }
     }
@@ -524,28 +784,47 @@ impl<'src> Parser<'src> {
         }
     }
 
-    fn identifier_constant(&mut self, name: Token) -> u8 {
-        self.make_constant(Value::ObjString(name.lexeme.to_string().clone()))
+    fn identifier_constant(&mut self, name: Token) -> usize {
+        let r = self.intern(name.lexeme);
+        self.make_constant(Value::obj(r))
+    }
+
+    /// Resolves a global variable to its slot in the VM-wide global table,
+    /// adding a slot the first time the name is seen.
+    fn global_slot(&mut self, name: Token) -> usize {
+        let name = self.intern(name.lexeme);
+        let slot = self.vm.declare_global(name);
+        if slot > MAX_LONG_OPERAND {
+            self.error("Too many global variables.");
+            return 0;
+        }
+        slot
     }
 
     fn identifiers_equal(&self, a: &Token, b: &Token) -> bool {
         a.lexeme == b.lexeme
     }
 
-    fn resolve_local(&mut self, name: Token) -> Option<u8> {
-        for (i, local) in self.compiler.locals.iter().rev().enumerate() {
-            if self.identifiers_equal(&name, &local.name) {
-                if local.depth == -1 {
-                    self.error("Cannot read local variable in its own initializer.");
-                }
-                return Some(i as u8);
+    fn resolve_local(&mut self, name: Token) -> Option<usize> {
+        let (slot, initialized) = self.compiler.resolve_local(&name)?;
+        if !initialized {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot)
+    }
+
+    fn resolve_upvalue(&mut self, name: Token) -> Option<u8> {
+        match self.compiler.resolve_upvalue(&name) {
+            Ok(upvalue) => upvalue,
+            Err(msg) => {
+                self.error(msg);
+                None
             }
         }
-        return None;
     }
 
     fn  add_local(&mut self, name: Token<'src>) {
-        if self.compiler.locals.len() == USIZE_COUNT {
+        if self.compiler.locals.len() > MAX_LONG_OPERAND {
             self.error("Too many local variables in function.");
             return;
         }
@@ -554,7 +833,7 @@ impl<'src> Parser<'src> {
         self.compiler.locals.push(local);
     }
 
-    fn parse_variable(&mut self, err_msg: &str) -> u8 {
+    fn parse_variable(&mut self, err_msg: &str) -> usize {
         self.consume(TokenType::Identifier, err_msg);
 
         self.declare_variable();
@@ -562,23 +841,44 @@ impl<'src> Parser<'src> {
             return 0;
         }
 
-        self.identifier_constant(self.previous)
+        self.global_slot(self.previous)
     }
 
     fn mark_initialized(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
         let last = self.compiler.locals.last_mut().unwrap();
         last.depth = self.compiler.scope_depth
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, global: usize) {
         if self.compiler.scope_depth > 0 {
             self.mark_initialized();
             return;
         }
-        self.emit_bytes(OpCode::OpDefineGlobal, global);
+        self.emit_indexed(OpCode::OpDefineGlobal, global);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_type(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
     }
 
-    fn and(&mut self, can_assign: bool) {
+    fn and(&mut self, _can_assign: bool) {
         let end_jump = self.emit_jump(OpCode::OpJumpIfFalse);
 
         self.emit_byte(OpCode::OpPop);
@@ -607,7 +907,7 @@ impl<'src> Parser<'src> {
     }
 
     fn get_rule(&self, tk_type: TokenType) -> &ParseRule<'src> {
-        return self.rules.get(&tk_type).expect("<TokenType, ParseRule> pair not found.");
+        self.rules.get(&tk_type).expect("<TokenType, ParseRule> pair not found.")
     }
 
     fn expression(&mut self) {
@@ -622,6 +922,124 @@ impl<'src> Parser<'src> {
         self.consume(TokenType::RightBrace, "Expect '}' after block.");
     }
 
+    fn function(&mut self, fn_type: FunctionType) {
+        let enclosing = std::mem::replace(&mut self.compiler, Compiler::new(fn_type));
+        self.compiler.enclosing = Some(Box::new(enclosing));
+        self.compiler.function.name = self.previous.lexeme.to_string();
+
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.compiler.function.arity += 1;
+                if self.compiler.function.arity > u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_type(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // The callee's frame is discarded wholesale on return, so there is
+        // no need to end the scope.
+        let compiler = self.end_compiler();
+        let function = self.alloc(Obj::Function(compiler.function));
+        let constant = self.make_constant(Value::obj(function));
+        self.emit_indexed(OpCode::OpClosure, constant);
+
+        // Each upvalue is a flags byte followed by the slot it captures: bit
+        // 0 marks a local of the enclosing function, bit 1 a 24-bit slot.
+        for upvalue in compiler.upvalues {
+            match u8::try_from(upvalue.index) {
+                Ok(index) => {
+                    self.emit_u8(upvalue.is_local as u8);
+                    self.emit_u8(index);
+                }
+                Err(_) => {
+                    self.emit_u8(upvalue.is_local as u8 | 2);
+                    let line = self.previous.line;
+                    self.current_chunk().write_u24(upvalue.index, line);
+                }
+            }
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let constant = self.identifier_constant(self.previous);
+
+        let fn_type = if self.previous.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(fn_type);
+        self.emit_indexed(OpCode::OpMethod, constant);
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous;
+        let name_constant = self.identifier_constant(self.previous);
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::OpClass, name_constant);
+        let global = if self.compiler.scope_depth > 0 {
+            0
+        } else {
+            self.global_slot(class_name)
+        };
+        self.define_variable(global);
+
+        self.class_compilers.push(ClassCompiler { has_superclass: false });
+
+        if self.match_type(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if self.identifiers_equal(&class_name, &self.previous) {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // Keep the superclass in a local named `super` so that methods
+            // capture it as an upvalue.
+            self.begin_scope();
+            self.add_local(Token::new(TokenType::Super, self.previous.line, "super"));
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit_byte(OpCode::OpInherit);
+            self.class_compilers.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Load the class back onto the stack so methods can be bound to it.
+        self.named_variable(&class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::OpPop);
+
+        if self.class_compilers.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
 
@@ -637,8 +1055,16 @@ impl<'src> Parser<'src> {
     }
 
     fn expression_statement(&mut self) {
+        let start = self.current_chunk().code.len();
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        if self.echo
+            && self.compiler.fn_type == FunctionType::Script
+            && self.compiler.scope_depth == 0
+            && start == self.declaration_start
+        {
+            self.trailing_expression = Some(self.current_chunk().code.len());
+        }
         self.emit_byte(OpCode::OpPop);
     }
 
@@ -714,6 +1140,24 @@ impl<'src> Parser<'src> {
         self.emit_byte(OpCode::OpPrint);
     }
 
+    fn return_statement(&mut self) {
+        if self.compiler.fn_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_type(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.fn_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::OpReturn);
+        }
+    }
+
     fn while_statement(&mut self) {
         let loop_start = self.current_chunk().code.len();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
@@ -754,7 +1198,11 @@ impl<'src> Parser<'src> {
     }
 
     fn declaration(&mut self) {
-        if self.match_type(TokenType::Var) {
+        if self.match_type(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_type(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_type(TokenType::Var) {
             self.var_declaration();
         } else {
             self.statement();
@@ -772,6 +1220,8 @@ impl<'src> Parser<'src> {
             self.for_statement();
         } else if self.match_type(TokenType::If) {
             self.if_statement();
+        } else if self.match_type(TokenType::Return) {
+            self.return_statement();
         } else if self.match_type(TokenType::While) {
             self.while_statement();
         } else if self.match_type(TokenType::LeftBrace) {
@@ -783,28 +1233,64 @@ impl<'src> Parser<'src> {
         }
     }
 
+    /// Allocates `obj` in the VM heap.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let r = self.vm.heap.alloc(obj);
+        self.collect_if_needed(r);
+        r
+    }
+
+    fn intern(&mut self, s: &str) -> ObjRef {
+        let r = self.vm.heap.intern(s);
+        self.collect_if_needed(r);
+        r
+    }
+
+    /// Collects garbage if the heap is due, keeping `r` and the constants of
+    /// the functions still being compiled alive. Those constants are not
+    /// reachable from the VM yet, so they are marked here.
+    fn collect_if_needed(&mut self, r: ObjRef) {
+        if self.vm.heap.should_collect() {
+            self.vm.heap.mark_object(r);
+            let mut compiler = Some(&self.compiler);
+            while let Some(c) = compiler {
+                for &constant in &c.function.chunk.constants {
+                    self.vm.heap.mark_value(constant);
+                }
+                compiler = c.enclosing.as_deref();
+            }
+            self.vm.collect_garbage();
+        }
+    }
+
     fn current_chunk(&mut self) -> &mut Chunk {
         &mut self.compiler.function.chunk
     }
 
+    fn had_error(&self) -> bool {
+        !self.diagnostics.is_empty()
+    }
+
     fn error_at(&mut self, token: Token, message: &str) {
 
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
 
-        if token.token_type == TokenType::Eof {
-            eprint!(" at end");
-        } else if token.token_type == TokenType::Error {
-            // Nothing.
-        } else {
-            eprint!(" at {}'", token.lexeme);
-        }
+        let lexeme = match token.token_type {
+            TokenType::Eof => Some(String::new()),
+            TokenType::Error => None,
+            _ => Some(token.lexeme.to_string()),
+        };
 
-        eprint!(": {}\n", message);
-        self.had_error = true;
+        self.diagnostics.push(Diagnostic {
+            kind: ErrorKind::Compile,
+            line: token.line,
+            column: Some(token.column),
+            lexeme,
+            message: message.to_string(),
+        });
     }
 
     fn error(&mut self, message: &str) {
@@ -0,0 +1,234 @@
+//! Human-readable listings of compiled bytecode.
+//!
+//! [`Chunk::instruction`] decodes a single instruction into an
+//! [`Instruction`], resolving constants, global names and jump targets.
+//! The `disassemble_*` methods render those as text into any [`fmt::Write`],
+//! or into an [`io::Write`] through [`Chunk::write_disassembly`].
+
+use std::fmt;
+use std::io;
+use crate::chunk::{Chunk, OpCode};
+use crate::function::Function;
+use crate::heap::{Heap, Obj};
+use crate::value::Value;
+use crate::vm::VM;
+
+/// One decoded instruction.
+#[derive(Clone, Debug)]
+pub struct Instruction {
+    pub offset: usize,
+    pub line: usize,
+    pub op: OpCode,
+    pub operand: Operand,
+    /// Offset of the following instruction.
+    pub next: usize,
+}
+
+/// An instruction's operands, resolved against its chunk and VM.
+#[derive(Clone, Debug)]
+pub enum Operand {
+    None,
+    /// A local slot, upvalue index or argument count.
+    Index(usize),
+    Constant { index: usize, value: Value },
+    /// A global slot, with its name if the VM has declared it.
+    Global { slot: usize, name: Option<String> },
+    Jump { target: usize },
+    Closure { index: usize, function: Value, captures: Vec<Capture> },
+}
+
+/// A variable captured by `OP_CLOSURE`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capture {
+    pub offset: usize,
+    /// Whether `index` is a local slot of the enclosing function rather
+    /// than one of its upvalues.
+    pub is_local: bool,
+    pub index: usize,
+}
+
+impl Chunk {
+    /// Decodes the instruction at `offset`.
+    pub fn instruction(&self, offset: usize, vm: &VM) -> Instruction {
+        let op = self.opcode(offset);
+        let (operand, next) = match op {
+            OpCode::OpConstant
+            | OpCode::OpConstantLong
+            | OpCode::OpGetProperty
+            | OpCode::OpSetProperty
+            | OpCode::OpGetSuper
+            | OpCode::OpClass
+            | OpCode::OpMethod
+            | OpCode::OpGetPropertyLong
+            | OpCode::OpSetPropertyLong
+            | OpCode::OpGetSuperLong
+            | OpCode::OpClassLong
+            | OpCode::OpMethodLong => {
+                let (index, next) = self.read_index(offset);
+                (Operand::Constant { index, value: self.constants[index] }, next)
+            }
+            OpCode::OpDefineGlobal
+            | OpCode::OpDefineGlobalLong
+            | OpCode::OpGetGlobal
+            | OpCode::OpGetGlobalLong
+            | OpCode::OpSetGlobal
+            | OpCode::OpSetGlobalLong => {
+                let (slot, next) = self.read_index(offset);
+                let name = vm.global_name(slot).map(str::to_string);
+                (Operand::Global { slot, name }, next)
+            }
+            OpCode::OpGetLocal
+            | OpCode::OpGetLocalLong
+            | OpCode::OpSetLocal
+            | OpCode::OpSetLocalLong
+            | OpCode::OpGetUpvalue
+            | OpCode::OpSetUpvalue
+            | OpCode::OpCall => {
+                let (index, next) = self.read_index(offset);
+                (Operand::Index(index), next)
+            }
+            OpCode::OpJumpIfFalse | OpCode::OpJump => {
+                let next = offset + 3;
+                let jump = (self.code[offset + 1] as usize) << 8 | self.code[offset + 2] as usize;
+                (Operand::Jump { target: next + jump }, next)
+            }
+            OpCode::OpJumpIfFalseLong | OpCode::OpJumpLong => {
+                let next = offset + 4;
+                (Operand::Jump { target: next + self.read_u24(offset + 1) }, next)
+            }
+            OpCode::OpLoop => {
+                let next = offset + 3;
+                let jump = (self.code[offset + 1] as usize) << 8 | self.code[offset + 2] as usize;
+                (Operand::Jump { target: next - jump }, next)
+            }
+            OpCode::OpLoopLong => {
+                let next = offset + 4;
+                (Operand::Jump { target: next - self.read_u24(offset + 1) }, next)
+            }
+            OpCode::OpClosure | OpCode::OpClosureLong => self.closure_operand(offset, vm.heap()),
+            _ => (Operand::None, offset + 1),
+        };
+
+        Instruction { offset, line: self.lines[offset], op, operand, next }
+    }
+
+    fn closure_operand(&self, offset: usize, heap: &Heap) -> (Operand, usize) {
+        let (index, mut next) = self.read_index(offset);
+        let function = self.constants[index];
+        let upvalue_count = match function.as_obj().map(|r| heap.get(r)) {
+            Some(Obj::Function(function)) => function.upvalue_count,
+            _ => 0,
+        };
+
+        let mut captures = Vec::with_capacity(upvalue_count);
+        for _ in 0..upvalue_count {
+            let flags = self.code[next];
+            let (index, after) = if flags & 2 != 0 {
+                (self.read_u24(next + 1), next + 4)
+            } else {
+                (self.code[next + 1] as usize, next + 2)
+            };
+            captures.push(Capture { offset: next, is_local: flags & 1 == 1, index });
+            next = after;
+        }
+        (Operand::Closure { index, function, captures }, next)
+    }
+
+    /// Decodes every instruction in the chunk.
+    pub fn instructions(&self, vm: &VM) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let instruction = self.instruction(offset, vm);
+            offset = instruction.next;
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    /// Writes a listing of the whole chunk under a `== name ==` header.
+    pub fn disassemble_chunk<W: fmt::Write>(
+        &self,
+        name: &str,
+        vm: &VM,
+        out: &mut W,
+    ) -> fmt::Result {
+        writeln!(out, "== {} ==", name)?;
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            offset = self.disassemble_instruction(offset, vm, out)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the instruction at `offset` and returns the offset of the next.
+    pub fn disassemble_instruction<W: fmt::Write>(
+        &self,
+        offset: usize,
+        vm: &VM,
+        out: &mut W,
+    ) -> Result<usize, fmt::Error> {
+        let instruction = self.instruction(offset, vm);
+        write!(out, "{:04} ", offset)?;
+        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
+            write!(out, "{:>4} ", "|")?;
+        } else {
+            write!(out, "{:4} ", instruction.line)?;
+        }
+
+        let name = instruction.op.name();
+        let heap = vm.heap();
+        match &instruction.operand {
+            Operand::None => writeln!(out, "{}", name)?,
+            Operand::Index(index) => writeln!(out, "{:<16} {:4}", name, index)?,
+            Operand::Constant { index, value } => {
+                writeln!(out, "{:<16} {:4} '{}'", name, index, value.display(heap))?
+            }
+            Operand::Global { slot, name: global } => {
+                writeln!(out, "{:<16} {:4} '{}'", name, slot, global.as_deref().unwrap_or("?"))?
+            }
+            Operand::Jump { target } => writeln!(out, "{:<16} {:4} -> {}", name, offset, target)?,
+            Operand::Closure { index, function, captures } => {
+                writeln!(out, "{:<16} {:4} {}", name, index, function.display(heap))?;
+                for capture in captures {
+                    let kind = if capture.is_local { "local" } else { "upvalue" };
+                    let (at, index) = (capture.offset, capture.index);
+                    writeln!(out, "{:04}    |                     {} {}", at, kind, index)?;
+                }
+            }
+        }
+        Ok(instruction.next)
+    }
+
+    /// Like [`disassemble_chunk`](Chunk::disassemble_chunk), for byte sinks
+    /// such as files and standard output.
+    pub fn write_disassembly<W: io::Write>(
+        &self,
+        name: &str,
+        vm: &VM,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let mut listing = String::new();
+        self.disassemble_chunk(name, vm, &mut listing)
+            .map_err(|_| io::Error::other("formatting failed"))?;
+        out.write_all(listing.as_bytes())
+    }
+}
+
+/// Lists `function` followed by every function nested in its constants.
+pub(crate) fn disassemble_function<W: fmt::Write>(
+    function: &Function,
+    vm: &VM,
+    out: &mut W,
+) -> fmt::Result {
+    let name = if function.name.is_empty() { "<script>" } else { &function.name };
+    function.chunk.disassemble_chunk(name, vm, out)?;
+    for constant in &function.chunk.constants {
+        if let Some(Obj::Function(nested)) = constant.as_obj().map(|r| vm.heap().get(r)) {
+            writeln!(out)?;
+            disassemble_function(nested, vm, out)?;
+        }
+    }
+    Ok(())
+}
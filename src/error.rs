@@ -0,0 +1,119 @@
+use std::error::Error;
+use std::fmt;
+use crate::serialize::BytecodeError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Compile,
+    Runtime,
+    /// Precompiled bytecode that could not be loaded.
+    Bytecode,
+}
+
+/// A single problem found while compiling or running a script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub kind: ErrorKind,
+    pub line: usize,
+    /// One-based column of the offending token. Runtime errors only know
+    /// the line, so they leave this empty.
+    pub column: Option<usize>,
+    /// The offending token, empty at end of input. `None` for runtime errors
+    /// and for errors reported by the scanner itself.
+    pub lexeme: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::Compile => {
+                write!(f, "[line {}] Error", self.line)?;
+                match &self.lexeme {
+                    Some(lexeme) if lexeme.is_empty() => write!(f, " at end")?,
+                    Some(lexeme) => write!(f, " at '{}'", lexeme)?,
+                    None => (),
+                }
+                write!(f, ": {}", self.message)
+            }
+            ErrorKind::Runtime => write!(f, "{}", self.message),
+            ErrorKind::Bytecode => write!(f, "Invalid bytecode: {}", self.message),
+        }
+    }
+}
+
+/// One active call at the point a runtime error was raised.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFrame {
+    /// Name of the function, or empty for the top-level script.
+    pub function: String,
+    pub line: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.function.is_empty() {
+            write!(f, "[line {}] in script", self.line)
+        } else {
+            write!(f, "[line {}] in {}()", self.line, self.function)
+        }
+    }
+}
+
+/// Error returned by [`VM::interpret`](crate::vm::VM::interpret).
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoxError {
+    pub kind: ErrorKind,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Innermost call first. Empty for compile errors.
+    pub trace: Vec<TraceFrame>,
+}
+
+impl LoxError {
+    pub fn compile(diagnostics: Vec<Diagnostic>) -> Self {
+        LoxError {
+            kind: ErrorKind::Compile,
+            diagnostics,
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn bytecode(err: BytecodeError) -> Self {
+        LoxError {
+            kind: ErrorKind::Bytecode,
+            diagnostics: vec![Diagnostic {
+                kind: ErrorKind::Bytecode,
+                line: 0,
+                column: None,
+                lexeme: None,
+                message: err.to_string(),
+            }],
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn runtime(diagnostic: Diagnostic, trace: Vec<TraceFrame>) -> Self {
+        LoxError {
+            kind: ErrorKind::Runtime,
+            diagnostics: vec![diagnostic],
+            trace,
+        }
+    }
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for LoxError {}
@@ -1,17 +1,28 @@
 use std::fmt;
 use crate::chunk::*;
+use crate::heap::ObjRef;
+use crate::value::Value;
+use crate::vm::VM;
 
 #[derive(Debug)]
 pub struct Function {
     pub arity: usize,
+    pub upvalue_count: usize,
     pub chunk: Chunk,
     pub name: String,
 }
 
+impl Default for Function {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Function {
     pub fn new() -> Self {
         Function {
             arity: 0,
+            upvalue_count: 0,
             chunk: Chunk::new(),
             name: "".to_string(),
         }
@@ -34,6 +45,7 @@ impl Clone for Function {
     fn clone(&self) -> Self {
         Self {
             arity: self.arity,
+            upvalue_count: self.upvalue_count,
             chunk: self.chunk.clone(),
             name: self.name.clone(),
         }
@@ -48,4 +60,66 @@ impl fmt::Display for Function {
             write!(f, "<fn {}>", self.name)
         }
     }
-}
\ No newline at end of file
+}
+
+/// A captured variable. While open it refers to a live stack slot; once the
+/// slot goes out of scope the value is moved into `closed`.
+#[derive(Debug)]
+pub struct Upvalue {
+    pub location: usize,
+    pub closed: Option<Value>,
+}
+
+impl Upvalue {
+    pub fn new(location: usize) -> Self {
+        Upvalue {
+            location,
+            closed: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+impl Closure {
+    pub fn new(function: ObjRef, upvalues: Vec<ObjRef>) -> Self {
+        Closure { function, upvalues }
+    }
+}
+
+/// Signature of host functions callable from Lox. Returning `Err` raises a
+/// runtime error with the given message. A native may run further scripts
+/// through the VM it is given; they return to it when they finish or fail.
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl NativeFunction {
+    pub fn new(name: &str, arity: usize, function: NativeFn) -> Self {
+        NativeFunction {
+            name: name.to_string(),
+            arity,
+            function,
+        }
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "NativeFunction({})", self.name)
+    }
+}
+
+impl fmt::Display for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "<native fn>")
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+use crate::class::{BoundMethod, Class, Instance};
+use crate::function::{Closure, Function, NativeFunction, Upvalue};
+use crate::value::Value;
+
+const INITIAL_THRESHOLD: usize = 1024 * 1024;
+const GROW_FACTOR: usize = 2;
+
+/// Handle to an object living in the [`Heap`]. Handles are plain indices, so
+/// copying a `Value` never copies the object behind it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+impl ObjRef {
+    #[cfg(feature = "nan-boxing")]
+    pub(crate) fn new(index: usize) -> Self {
+        ObjRef(index)
+    }
+
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Debug)]
+pub enum Obj {
+    /// Shares its allocation with the intern table's key.
+    String(Rc<str>),
+    Function(Function),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(NativeFunction),
+}
+
+impl Obj {
+    /// Rough number of bytes owned by the object, used to pace collections.
+    fn size(&self) -> usize {
+        let owned = match self {
+            Obj::String(s) => s.len(),
+            Obj::Function(function) => {
+                let chunk = &function.chunk;
+                chunk.code.capacity()
+                    + chunk.lines.capacity() * mem::size_of::<usize>()
+                    + chunk.constants.capacity() * mem::size_of::<Value>()
+                    + function.name.capacity()
+            }
+            Obj::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<ObjRef>(),
+            Obj::Class(class) => {
+                class.name.capacity()
+                    + class.methods.capacity() * mem::size_of::<(ObjRef, ObjRef)>()
+            }
+            Obj::Instance(instance) => {
+                instance.fields.capacity() * mem::size_of::<(ObjRef, Value)>()
+            }
+            Obj::Upvalue(_) | Obj::BoundMethod(_) | Obj::Native(_) => 0,
+        };
+        mem::size_of::<Obj>() + owned
+    }
+}
+
+struct HeapEntry {
+    obj: Obj,
+    marked: bool,
+}
+
+/// Owner of every Lox object. Memory is reclaimed by a tracing mark-and-sweep
+/// collector: the VM marks its roots, then [`trace`](Heap::trace) and
+/// [`sweep`](Heap::sweep) free whatever is unreachable, cycles included.
+pub struct Heap {
+    entries: Vec<Option<HeapEntry>>,
+    free: Vec<usize>,
+    /// Interned strings, keyed by the same allocation their objects hold.
+    /// Entries are weak: a string only reachable from here is still
+    /// collected.
+    strings: HashMap<Rc<str>, ObjRef>,
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Lower bound for `next_gc`, so small heaps are not collected constantly.
+    threshold: usize,
+    /// After a collection the next one is due once the heap has grown to this
+    /// many times its surviving size.
+    pub grow_factor: usize,
+    /// Collect before every allocation. Useful for shaking out rooting bugs.
+    pub stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            entries: Vec::new(),
+            free: Vec::new(),
+            strings: HashMap::new(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_THRESHOLD,
+            threshold: INITIAL_THRESHOLD,
+            grow_factor: GROW_FACTOR,
+            stress: false,
+        }
+    }
+
+    /// Moves `obj` into the heap. Never collects; callers decide when it is
+    /// safe to do so via [`should_collect`](Heap::should_collect). Strings
+    /// must go through [`intern`](Heap::intern) instead.
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        debug_assert!(!matches!(obj, Obj::String(_)), "Strings must be interned");
+        self.bytes_allocated += obj.size();
+        self.insert(HeapEntry { obj, marked: false })
+    }
+
+    fn insert(&mut self, entry: HeapEntry) -> ObjRef {
+        let entry = Some(entry);
+        match self.free.pop() {
+            Some(idx) => {
+                self.entries[idx] = entry;
+                ObjRef(idx)
+            }
+            None => {
+                self.entries.push(entry);
+                ObjRef(self.entries.len() - 1)
+            }
+        }
+    }
+
+    /// Returns the one string object holding `s`, allocating it if needed.
+    /// Equal strings therefore always share a handle, so comparing handles
+    /// compares contents.
+    pub fn intern(&mut self, s: &str) -> ObjRef {
+        if let Some(&r) = self.strings.get(s) {
+            return r;
+        }
+
+        let s: Rc<str> = Rc::from(s);
+        let obj = Obj::String(Rc::clone(&s));
+        self.bytes_allocated += obj.size();
+        let r = self.insert(HeapEntry { obj, marked: false });
+        self.strings.insert(s, r);
+        r
+    }
+
+    /// Current size of the object `r`, as charged to the heap.
+    pub fn size_of(&self, r: ObjRef) -> usize {
+        self.get(r).size()
+    }
+
+    /// Charges the heap for `bytes` an object grew by after allocation, such
+    /// as an instance's field table resizing, so growth paces collections
+    /// just like new objects do.
+    pub fn account(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
+    }
+
+    /// Looks up an already interned string without allocating.
+    pub fn find_string(&self, s: &str) -> Option<ObjRef> {
+        self.strings.get(s).copied()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn set_threshold(&mut self, bytes: usize) {
+        self.threshold = bytes;
+        self.next_gc = bytes;
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.entries.len() - self.free.len()
+    }
+
+    pub fn get(&self, r: ObjRef) -> &Obj {
+        &self.entries[r.0].as_ref().expect("Dangling object reference").obj
+    }
+
+    pub fn get_mut(&mut self, r: ObjRef) -> &mut Obj {
+        &mut self.entries[r.0].as_mut().expect("Dangling object reference").obj
+    }
+
+    pub fn string(&self, r: ObjRef) -> &str {
+        match self.get(r) {
+            Obj::String(s) => s,
+            other => panic!("Expected string, found {:?}", other),
+        }
+    }
+
+    pub fn function(&self, r: ObjRef) -> &Function {
+        match self.get(r) {
+            Obj::Function(function) => function,
+            other => panic!("Expected function, found {:?}", other),
+        }
+    }
+
+    pub fn closure(&self, r: ObjRef) -> &Closure {
+        match self.get(r) {
+            Obj::Closure(closure) => closure,
+            other => panic!("Expected closure, found {:?}", other),
+        }
+    }
+
+    pub fn closure_mut(&mut self, r: ObjRef) -> &mut Closure {
+        match self.get_mut(r) {
+            Obj::Closure(closure) => closure,
+            other => panic!("Expected closure, found {:?}", other),
+        }
+    }
+
+    pub fn upvalue(&self, r: ObjRef) -> &Upvalue {
+        match self.get(r) {
+            Obj::Upvalue(upvalue) => upvalue,
+            other => panic!("Expected upvalue, found {:?}", other),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, r: ObjRef) -> &mut Upvalue {
+        match self.get_mut(r) {
+            Obj::Upvalue(upvalue) => upvalue,
+            other => panic!("Expected upvalue, found {:?}", other),
+        }
+    }
+
+    pub fn class(&self, r: ObjRef) -> &Class {
+        match self.get(r) {
+            Obj::Class(class) => class,
+            other => panic!("Expected class, found {:?}", other),
+        }
+    }
+
+    pub fn class_mut(&mut self, r: ObjRef) -> &mut Class {
+        match self.get_mut(r) {
+            Obj::Class(class) => class,
+            other => panic!("Expected class, found {:?}", other),
+        }
+    }
+
+    pub fn instance(&self, r: ObjRef) -> &Instance {
+        match self.get(r) {
+            Obj::Instance(instance) => instance,
+            other => panic!("Expected instance, found {:?}", other),
+        }
+    }
+
+    pub fn instance_mut(&mut self, r: ObjRef) -> &mut Instance {
+        match self.get_mut(r) {
+            Obj::Instance(instance) => instance,
+            other => panic!("Expected instance, found {:?}", other),
+        }
+    }
+
+    pub fn mark_value(&mut self, val: Value) {
+        if let Some(r) = val.as_obj() {
+            self.mark_object(r);
+        }
+    }
+
+    pub fn mark_object(&mut self, r: ObjRef) {
+        let entry = self.entries[r.0].as_mut().expect("Dangling object reference");
+        if entry.marked {
+            return;
+        }
+        entry.marked = true;
+        self.gray.push(r);
+    }
+
+    /// Marks everything reachable from the objects marked so far.
+    pub fn trace(&mut self) {
+        let mut children: Vec<Value> = Vec::new();
+        while let Some(r) = self.gray.pop() {
+            match self.get(r) {
+                Obj::String(_) | Obj::Native(_) => (),
+                Obj::Function(function) => children.extend(&function.chunk.constants),
+                Obj::Closure(closure) => {
+                    children.push(Value::obj(closure.function));
+                    children.extend(closure.upvalues.iter().map(|&u| Value::obj(u)));
+                }
+                Obj::Upvalue(upvalue) => children.extend(upvalue.closed),
+                Obj::Class(class) => {
+                    for (&name, &method) in &class.methods {
+                        children.push(Value::obj(name));
+                        children.push(Value::obj(method));
+                    }
+                }
+                Obj::Instance(instance) => {
+                    children.push(Value::obj(instance.class));
+                    for (&name, &val) in &instance.fields {
+                        children.push(Value::obj(name));
+                        children.push(val);
+                    }
+                }
+                Obj::BoundMethod(bound) => {
+                    children.push(bound.receiver);
+                    children.push(Value::obj(bound.method));
+                }
+            }
+
+            for child in children.drain(..) {
+                self.mark_value(child);
+            }
+        }
+    }
+
+    /// Frees every unmarked object and clears the marks on the survivors.
+    /// Returns the number of objects freed.
+    pub fn sweep(&mut self) -> usize {
+        let mut freed = 0;
+        let mut live_bytes = 0;
+        for (idx, slot) in self.entries.iter_mut().enumerate() {
+            match slot {
+                Some(entry) if entry.marked => {
+                    entry.marked = false;
+                    live_bytes += entry.obj.size();
+                }
+                Some(entry) => {
+                    if let Obj::String(s) = &entry.obj {
+                        self.strings.remove(&**s);
+                    }
+                    *slot = None;
+                    self.free.push(idx);
+                    freed += 1;
+                }
+                None => (),
+            }
+        }
+
+        self.bytes_allocated = live_bytes;
+        self.next_gc = self.threshold.max(live_bytes * self.grow_factor);
+        freed
+    }
+}
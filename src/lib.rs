@@ -0,0 +1,51 @@
+//! A bytecode virtual machine for the Lox language.
+//!
+//! The usual entry point is [`VM`]: create one, optionally register host
+//! functions with [`VM::define_native`], and feed it source code. Failures
+//! come back as a [`LoxError`] holding the diagnostics, so embedders decide
+//! how to present them.
+//!
+//! ```
+//! use rslox::{Value, VM};
+//!
+//! fn double(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+//!     let n = f64::try_from(args[0])?;
+//!     Ok(Value::from(n * 2.0))
+//! }
+//!
+//! let mut vm = VM::new();
+//! vm.define_native("double", 1, double);
+//! vm.set_global("base", 21.0);
+//!
+//! vm.interpret("var answer = double(base);").unwrap();
+//! assert_eq!(f64::try_from(vm.get_global("answer").unwrap()), Ok(42.0));
+//! ```
+
+pub mod chunk;
+pub mod class;
+pub mod compiler;
+pub mod disassembler;
+pub mod error;
+pub mod function;
+pub mod heap;
+pub mod natives;
+pub mod scanner;
+pub mod serialize;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod value;
+pub mod verify;
+pub mod vm;
+
+pub use crate::chunk::{Chunk, OpCode};
+pub use crate::compiler::Parser;
+pub use crate::disassembler::{Capture, Instruction, Operand};
+pub use crate::error::{Diagnostic, ErrorKind, LoxError, TraceFrame};
+pub use crate::function::NativeFn;
+pub use crate::heap::{Heap, ObjRef};
+pub use crate::serialize::BytecodeError;
+#[cfg(feature = "trace")]
+pub use crate::trace::Tracer;
+pub use crate::value::{Value, ValueKind};
+pub use crate::verify::{VerifyError, VerifyErrorKind};
+pub use crate::vm::VM;
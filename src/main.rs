@@ -1,59 +1,353 @@
 use std::{env, io};
-use std::io::{stdout, Write};
-use crate::vm::{VM, InterpretResult};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::Instant;
 use std::fs;
 
-mod chunk;
-mod value;
-mod vm;
-mod compiler;
-mod scanner;
-mod function;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use rslox::scanner::is_incomplete;
+use rslox::serialize::MAGIC;
+use rslox::{ErrorKind, LoxError, Value, VM};
 
 fn main() {
 
-    let mut argv = env::args();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut vm = cli_vm();
+    configure_tracing(&mut vm, &mut args);
+
+    match args.as_slice() {
+        [] => repl(&mut vm),
+        [command, input, flag, output] if command == "compile" && flag == "-o" => {
+            compile_file(input, output)
+        }
+        [flag, path] if flag == "--disassemble" => disassemble_file(path),
+        [path] => run_file(&mut vm, path),
+        _ => usage(),
+    }
+}
+
+/// A VM that reports errors on stderr as they happen.
+fn cli_vm() -> VM {
     let mut vm = VM::new();
+    vm.set_diagnostics(Box::new(io::stderr()));
+    vm
+}
 
-    match argv.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&argv.nth(1).expect("Could not parse argv")),
-        _ => {
-            eprintln!("Usage: rslox [path]");
-            exit(64);
+fn usage() -> ! {
+    eprintln!("Usage: rslox [path]");
+    eprintln!("       rslox compile <in.lox> -o <out.loxc>");
+    eprintln!("       rslox --disassemble <path>");
+    eprintln!();
+    eprintln!("Tracing options, before the path:");
+    eprintln!("  --trace               trace every instruction to stderr");
+    eprintln!("  --trace-out <file>    trace into <file> instead");
+    eprintln!("  --trace-fn <name>     only trace <name>; may be repeated");
+    eprintln!("  --trace-lines <a-b>   only trace source lines a to b");
+    exit(64);
+}
+
+/// Removes the `--trace*` options from `args` and installs the tracer they
+/// describe.
+#[cfg(feature = "trace")]
+fn configure_tracing(vm: &mut VM, args: &mut Vec<String>) {
+    use rslox::Tracer;
+
+    let mut enabled = false;
+    let mut out = None;
+    let mut functions = Vec::new();
+    let mut lines = None;
+
+    let mut rest = Vec::new();
+    let mut iter = args.drain(..);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--trace" => {}
+            "--trace-out" => out = Some(iter.next().unwrap_or_else(|| usage())),
+            "--trace-fn" => functions.push(iter.next().unwrap_or_else(|| usage())),
+            "--trace-lines" => {
+                let range = iter.next().unwrap_or_else(|| usage());
+                lines = Some(parse_lines(&range).unwrap_or_else(|| usage()));
+            }
+            _ => {
+                rest.push(arg);
+                continue;
+            }
         }
+        enabled = true;
+    }
+    drop(iter);
+    *args = rest;
+
+    if !enabled {
+        return;
+    }
+    let mut tracer = match out {
+        Some(path) => Tracer::file(&path).unwrap_or_else(|err| {
+            eprintln!("Could not write '{}': {}", path, err);
+            exit(74);
+        }),
+        None => Tracer::stderr(),
+    };
+    for function in functions {
+        tracer = tracer.function(function);
     }
+    if let Some(lines) = lines {
+        tracer = tracer.lines(lines);
+    }
+    vm.set_tracer(Some(tracer));
 }
 
+#[cfg(not(feature = "trace"))]
+fn configure_tracing(_vm: &mut VM, args: &mut [String]) {
+    if args.iter().any(|arg| arg.starts_with("--trace")) {
+        eprintln!("rslox was built without the `trace` feature.");
+        exit(64);
+    }
+}
+
+/// Parses a line range such as `3-10`, or a single line.
+#[cfg(feature = "trace")]
+fn parse_lines(range: &str) -> Option<std::ops::RangeInclusive<usize>> {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    Some(start.trim().parse().ok()?..=end.trim().parse().ok()?)
+}
+
+/// Reads entries until end of input. An entry with an unclosed bracket or
+/// string continues onto the next line, and a blank line submits it anyway.
 pub fn repl(vm: &mut VM) {
-    let mut buf = String::new();
-    let stdin = io::stdin();
-    loop {
-        print!("> ");
-        stdout().flush().unwrap();
+    let mut editor = DefaultEditor::new().unwrap_or_else(|err| {
+        eprintln!("Could not start the line editor: {}", err);
+        exit(74);
+    });
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file just means a fresh start.
+        let _ = editor.load_history(path);
+    }
 
-        match stdin.read_line(&mut buf) {
-            Ok(0) | Err(_) => {
-                println!();
+    let mut entry = String::new();
+    loop {
+        let prompt = if entry.is_empty() { "> " } else { "... " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C abandons the entry being typed.
+            Err(ReadlineError::Interrupted) => {
+                entry.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("Could not read input: {}", err);
                 break;
             }
-            Ok(_) => {
-                vm.interpret(&buf);
+        };
+
+        if entry.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                let _ = editor.add_history_entry(line.trim());
+                run_command(vm, command);
+                continue;
             }
         }
-        buf.clear()
+
+        entry.push_str(&line);
+        entry.push('\n');
+        if !line.trim().is_empty() && is_incomplete(&entry) {
+            continue;
+        }
+        let source = std::mem::take(&mut entry);
+        if source.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(source.trim_end());
+
+        // Errors are reported by the VM itself.
+        if let Ok(value) = vm.interpret_repl(&source) {
+            echo(vm, value);
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("Could not save history to '{}': {}", path.display(), err);
+        }
     }
 }
 
-fn run_file(path: &str) {
-    let mut vm = VM::new();
-    let source = fs::read_to_string(path).expect("Could not open file");
-    let result = vm.interpret(source.as_str());
+fn echo(vm: &mut VM, value: Option<Value>) {
+    if let Some(value) = value {
+        let shown = value.repr(vm.heap()).to_string();
+        let _ = writeln!(vm.output(), "{}", shown);
+    }
+}
+
+const HELP: &str = "\
+:globals         list global variables and their values
+:dis <code>      show the bytecode compiled from <code>
+:load <path>     run a Lox source or bytecode file
+:reset           discard all globals and start over
+:trace on|off    trace every instruction to stderr
+:time <code>     run <code> and report how long it took
+:help            show this list";
+
+/// Runs a REPL command, given without its leading colon.
+fn run_command(vm: &mut VM, command: &str) {
+    let (name, arg) = match command.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (command, ""),
+    };
+
+    match (name, arg) {
+        ("globals", "") => {
+            let listing: String = vm
+                .globals()
+                .map(|(name, value)| format!("{} = {}\n", name, value.repr(vm.heap())))
+                .collect();
+            let _ = write!(vm.output(), "{}", listing);
+        }
+        ("dis", code) if !code.is_empty() => {
+            if let Ok(listing) = vm.disassemble(&as_statement(code)) {
+                let _ = write!(vm.output(), "{}", listing);
+            }
+        }
+        ("load", path) if !path.is_empty() => load(vm, path),
+        ("reset", "") => {
+            #[cfg(feature = "trace")]
+            let tracer = vm.set_tracer(None);
+            *vm = cli_vm();
+            #[cfg(feature = "trace")]
+            vm.set_tracer(tracer);
+        }
+        ("trace", "on") => set_tracing(vm, true),
+        ("trace", "off") => set_tracing(vm, false),
+        ("time", code) if !code.is_empty() => {
+            let start = Instant::now();
+            let result = vm.interpret_repl(&as_statement(code));
+            let elapsed = start.elapsed();
+            if let Ok(value) = result {
+                echo(vm, value);
+            }
+            let _ = writeln!(vm.output(), "Took {:.3} ms.", elapsed.as_secs_f64() * 1000.0);
+        }
+        ("help", "") => {
+            let _ = writeln!(vm.output(), "{}", HELP);
+        }
+        _ => {
+            let _ = writeln!(vm.output(), "Unknown command ':{}'. Type :help for a list.", command);
+        }
+    }
+}
+
+/// Adds the semicolon a bare expression typed after a command leaves out.
+fn as_statement(code: &str) -> String {
+    if code.ends_with(';') || code.ends_with('}') {
+        code.to_string()
+    } else {
+        format!("{};", code)
+    }
+}
+
+/// Runs a file from the REPL. Unlike `run_file`, failures leave the
+/// session running.
+fn load(vm: &mut VM, path: &str) {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Could not read '{}': {}", path, err);
+            return;
+        }
+    };
+    // Errors are reported by the VM itself.
+    if bytes.starts_with(MAGIC) {
+        let _ = vm.interpret_bytecode(&bytes);
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(source) => {
+                let _ = vm.interpret(&source);
+            }
+            Err(_) => eprintln!("'{}' is neither Lox source nor compiled bytecode.", path),
+        }
+    }
+}
+
+#[cfg(feature = "trace")]
+fn set_tracing(vm: &mut VM, on: bool) {
+    vm.set_tracer(on.then(rslox::Tracer::stderr));
+}
+
+#[cfg(not(feature = "trace"))]
+fn set_tracing(_vm: &mut VM, _on: bool) {
+    eprintln!("rslox was built without the `trace` feature.");
+}
+
+/// The REPL history file: `$RSLOX_HISTORY`, or `.rslox_history` in the
+/// home directory.
+fn history_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("RSLOX_HISTORY") {
+        return Some(PathBuf::from(path));
+    }
+    env::var_os("HOME").map(|home| Path::new(&home).join(".rslox_history"))
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Could not read '{}': {}", path, err);
+        exit(74);
+    })
+}
+
+/// Runs `path`, which may hold either source code or a compiled script.
+fn run_file(vm: &mut VM, path: &str) {
+    let bytes = read_file(path);
+    let result = if bytes.starts_with(MAGIC) {
+        vm.interpret_bytecode(&bytes)
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(source) => vm.interpret(&source),
+            Err(_) => {
+                eprintln!("'{}' is neither Lox source nor compiled bytecode.", path);
+                exit(65);
+            }
+        }
+    };
 
     match result {
-        InterpretResult::CompileError => exit(65),
-        InterpretResult::RuntimeError => exit(70),
-        InterpretResult::Ok => exit(0),
+        Ok(_) => exit(0),
+        Err(err) => fail(err),
     }
-}
\ No newline at end of file
+}
+
+fn compile_file(input: &str, output: &str) {
+    let mut vm = cli_vm();
+    let source = String::from_utf8(read_file(input)).unwrap_or_else(|_| {
+        eprintln!("'{}' is not valid UTF-8.", input);
+        exit(65);
+    });
+
+    let bytes = vm.compile(&source).unwrap_or_else(|err| fail(err));
+    if let Err(err) = fs::write(output, bytes) {
+        eprintln!("Could not write '{}': {}", output, err);
+        exit(74);
+    }
+}
+
+fn disassemble_file(path: &str) {
+    let mut vm = cli_vm();
+    let source = String::from_utf8(read_file(path)).unwrap_or_else(|_| {
+        eprintln!("'{}' is not valid UTF-8.", path);
+        exit(65);
+    });
+
+    let listing = vm.disassemble(&source).unwrap_or_else(|err| fail(err));
+    print!("{}", listing);
+}
+
+/// Exits with the status for `err`, which the VM has already reported.
+fn fail(err: LoxError) -> ! {
+    match err.kind {
+        ErrorKind::Compile | ErrorKind::Bytecode => exit(65),
+        ErrorKind::Runtime => exit(70),
+    }
+}
@@ -0,0 +1,11 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::value::Value;
+use crate::vm::VM;
+
+/// Seconds since the Unix epoch, as a number.
+pub fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::number(now.as_secs_f64()))
+}
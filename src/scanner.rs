@@ -3,15 +3,19 @@ pub struct Scanner<'src> {
     current: usize,
     src: &'src str,
     line: usize,
+    line_start: usize,
+    start_column: usize,
 }
 
 impl<'src> Scanner<'src> {
-    pub fn new(source: &str) -> Scanner {
+    pub fn new(source: &'src str) -> Scanner<'src> {
         Scanner {
             start: 0,
             current: 0,
             src: source,
             line: 1,
+            line_start: 0,
+            start_column: 1,
         }
     }
 
@@ -19,6 +23,7 @@ impl<'src> Scanner<'src> {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_column = self.start - self.line_start + 1;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -65,17 +70,18 @@ impl<'src> Scanner<'src> {
         char
     }
 
+    /// The current byte, or NUL at the end of input.
     fn peek(&self) -> u8 {
         if self.is_at_end() {
-            b'0'
+            b'\0'
         } else {
             self.src.as_bytes()[self.current]
         }
     }
 
-    fn peek_next(&self)  -> u8 {
-        if self.current > self.src.len() - 2 {
-            b'0'
+    fn peek_next(&self) -> u8 {
+        if self.current + 1 >= self.src.len() {
+            b'\0'
         } else {
             self.src.as_bytes()[self.current + 1]
         }
@@ -98,18 +104,15 @@ impl<'src> Scanner<'src> {
                     self.advance();
                 }
                 b'\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
-                b'/' => {
-                    if self.peek_next() == b'/' {
-                        while self.peek() != b'\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                b'/' if self.peek_next() == b'/' => {
+                    // A comment goes until the end of the line; the newline
+                    // itself is consumed by the next iteration.
+                    while self.peek() != b'\n' && !self.is_at_end() {
+                        self.advance();
                     }
-                    break;
                 }
                 _ => return,
             }
@@ -165,6 +168,11 @@ impl<'src> Scanner<'src> {
         }
     }
 
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn is_at_end(&self) -> bool {
         self.current == self.src.len()
     }
@@ -173,6 +181,7 @@ impl<'src> Scanner<'src> {
         Token {
             token_type,
             line: self.line,
+            column: self.start_column,
             lexeme: &self.src[self.start..self.current],
         }
     }
@@ -181,6 +190,7 @@ impl<'src> Scanner<'src> {
         Token {
             token_type: TokenType::Error,
             line: self.line,
+            column: self.start_column,
             lexeme: message,
         }
     }
@@ -212,10 +222,10 @@ impl<'src> Scanner<'src> {
 
     fn string(&mut self) -> Token<'src> {
         while self.peek() != b'"' && !self.is_at_end() {
-            if self.peek() == b'\n' {
-                self.line += 1
-            }
             self.advance();
+            if self.src.as_bytes()[self.current - 1] == b'\n' {
+                self.new_line();
+            }
         }
 
         if self.is_at_end() {
@@ -232,14 +242,17 @@ impl<'src> Scanner<'src> {
 pub struct Token<'src> {
     pub token_type: TokenType,
     pub line: usize,
+    pub column: usize,
     pub lexeme: &'src str,
 }
 
 impl<'src> Token<'src> {
+    /// Creates a synthetic token that does not come from the source text.
     pub fn new(token_type: TokenType, line: usize, lexeme: &'src str) -> Token<'src> {
         Token {
             token_type,
             line,
+            column: 0,
             lexeme,
         }
     }
@@ -296,6 +309,23 @@ pub enum TokenType {
     Eof,
 }
 
+/// Whether `source` stops in the middle of a string or inside an unclosed
+/// `(` or `{`, so that more lines are needed before it can be compiled.
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0i32;
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+            TokenType::Error if token.lexeme == "Unterminated string." => return true,
+            TokenType::Eof => return depth > 0,
+            _ => (),
+        }
+    }
+}
+
 fn is_alpha(c: u8) -> bool {
     c.is_ascii_alphabetic() || c == b'_'
 }
@@ -0,0 +1,403 @@
+//! The `.loxc` format: a compiled script and every function nested in it,
+//! saved so it can be run later without reparsing.
+//!
+//! All integers are little-endian. A file is laid out as:
+//!
+//! ```text
+//! magic       b"LOXC"
+//! version     u16
+//! globals     u32 count, then one string per global slot
+//! function    the top-level script, see below
+//! checksum    u32 CRC-32 of every preceding byte
+//! ```
+//!
+//! A function is its name (string), arity (u32), upvalue count (u32), code
+//! (u32 length and bytes), line table and constant pool. The line table is
+//! run-length encoded as a u32 run count followed by `(line, length)` u32
+//! pairs. Each constant is a tag byte followed by an f64 for numbers, a
+//! string, or a nested function. Strings are a u32 byte length and UTF-8. The
+//! top-level script must have an arity and upvalue count of zero.
+//!
+//! Global operands in the code are slots into the table of the VM that
+//! compiled the script. The globals section records each slot's name so a
+//! loading VM can map them onto its own slots.
+
+use std::error::Error;
+use std::fmt;
+use crate::chunk::{Chunk, OpCode, MAX_LONG_OPERAND};
+use crate::function::Function;
+use crate::heap::{Heap, Obj, ObjRef};
+use crate::value::{Value, ValueKind};
+use crate::verify::{verify, VerifyError};
+use crate::vm::VM;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Version 2 added the long forms of the closure, class, method, property
+/// and super opcodes.
+pub const VERSION: u16 = 2;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+/// How deeply functions may nest inside one another's constants. Loading
+/// recurses once per level, so this bounds the loader's stack use.
+pub const MAX_FUNCTION_DEPTH: usize = 256;
+
+/// Why a `.loxc` file could not be loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    BadChecksum,
+    /// The file ended in the middle of the named section.
+    Truncated(&'static str),
+    /// Bytes left over after the top-level function.
+    TrailingBytes,
+    InvalidString,
+    InvalidConstant(u8),
+    /// A function's line table covers more bytes than its code.
+    LineTableTooLong,
+    /// Functions nest deeper than [`MAX_FUNCTION_DEPTH`].
+    TooDeeplyNested,
+    /// The top-level function declares parameters or upvalues, which
+    /// nothing could supply when it runs.
+    InvalidScript,
+    /// A constant the format cannot represent, such as an instance.
+    Unserializable(&'static str),
+    /// A global operand refers to a slot missing from the globals section.
+    UnknownGlobal(usize),
+    /// A global's slot in the loading VM does not fit the operand the file
+    /// encoded it with.
+    GlobalOutOfRange(String),
+    /// A function's code failed verification.
+    Invalid(VerifyError),
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "Not a compiled Lox file."),
+            BytecodeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bytecode version {} (expected {}).", version, VERSION)
+            }
+            BytecodeError::BadChecksum => write!(f, "Checksum mismatch; the file is corrupt."),
+            BytecodeError::Truncated(section) => write!(f, "Unexpected end of file in {}.", section),
+            BytecodeError::TrailingBytes => write!(f, "Unexpected data after the script."),
+            BytecodeError::InvalidString => write!(f, "String is not valid UTF-8."),
+            BytecodeError::InvalidConstant(tag) => write!(f, "Unknown constant tag {}.", tag),
+            BytecodeError::LineTableTooLong => write!(f, "Line table is longer than the code."),
+            BytecodeError::TooDeeplyNested => {
+                write!(f, "Functions are nested more than {} deep.", MAX_FUNCTION_DEPTH)
+            }
+            BytecodeError::InvalidScript => {
+                write!(f, "The script must take no arguments and capture no variables.")
+            }
+            BytecodeError::Unserializable(ty) => write!(f, "Can't serialize a {} constant.", ty),
+            BytecodeError::UnknownGlobal(slot) => write!(f, "Unknown global slot {}.", slot),
+            BytecodeError::GlobalOutOfRange(name) => {
+                write!(f, "Too many globals to load '{}'.", name)
+            }
+            BytecodeError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for BytecodeError {}
+
+/// Encodes `script`, compiled by `vm`, as a `.loxc` file.
+pub fn serialize(vm: &VM, script: &Function) -> Result<Vec<u8>, BytecodeError> {
+    let mut writer = Writer { out: Vec::new(), heap: vm.heap() };
+    writer.out.extend_from_slice(MAGIC);
+    writer.u16(VERSION);
+
+    let names: Vec<&str> = (0..).map_while(|slot| vm.global_name(slot)).collect();
+    writer.u32(names.len());
+    for name in names {
+        writer.string(name);
+    }
+
+    writer.function(script)?;
+    let checksum = crc32(&writer.out);
+    writer.out.extend_from_slice(&checksum.to_le_bytes());
+    Ok(writer.out)
+}
+
+/// Decodes a `.loxc` file into `vm`'s heap, declaring its globals, and
+/// returns the top-level script function.
+pub fn deserialize(vm: &mut VM, bytes: &[u8]) -> Result<ObjRef, BytecodeError> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(BytecodeError::BadMagic);
+    }
+    if bytes.len() < MAGIC.len() + 2 + 4 {
+        return Err(BytecodeError::Truncated("header"));
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(BytecodeError::BadChecksum);
+    }
+
+    // Everything allocated while loading stays on the stack until the script
+    // is complete, so a collection cannot free half a function.
+    let base = vm.stack.len();
+    let mut reader = Reader { bytes: body, pos: MAGIC.len(), vm, globals: Vec::new(), depth: 0 };
+    let result = reader.script();
+    reader.vm.stack.truncate(base);
+    result
+}
+
+struct Writer<'a> {
+    out: Vec<u8>,
+    heap: &'a Heap,
+}
+
+impl Writer<'_> {
+    fn u16(&mut self, n: u16) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: usize) {
+        self.out.extend_from_slice(&(n as u32).to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len());
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), BytecodeError> {
+        self.string(&function.name);
+        self.u32(function.arity);
+        self.u32(function.upvalue_count);
+
+        let chunk = &function.chunk;
+        self.u32(chunk.code.len());
+        self.out.extend_from_slice(&chunk.code);
+        self.lines(&chunk.lines);
+
+        self.u32(chunk.constants.len());
+        for &constant in &chunk.constants {
+            self.constant(constant)?;
+        }
+        Ok(())
+    }
+
+    fn lines(&mut self, lines: &[usize]) {
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for &line in lines {
+            match runs.last_mut() {
+                Some((last, len)) if *last == line => *len += 1,
+                _ => runs.push((line, 1)),
+            }
+        }
+
+        self.u32(runs.len());
+        for (line, len) in runs {
+            self.u32(line);
+            self.u32(len);
+        }
+    }
+
+    fn constant(&mut self, constant: Value) -> Result<(), BytecodeError> {
+        let heap = self.heap;
+        match constant.kind() {
+            ValueKind::Number(n) => {
+                self.out.push(TAG_NUMBER);
+                self.out.extend_from_slice(&n.to_le_bytes());
+            }
+            ValueKind::Obj(r) => match heap.get(r) {
+                Obj::String(s) => {
+                    self.out.push(TAG_STRING);
+                    self.string(s);
+                }
+                Obj::Function(function) => {
+                    self.out.push(TAG_FUNCTION);
+                    self.function(function)?;
+                }
+                _ => return Err(BytecodeError::Unserializable(constant.type_name(heap))),
+            },
+            _ => return Err(BytecodeError::Unserializable(constant.type_name(heap))),
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    vm: &'a mut VM,
+    /// The loading VM's slot for each global slot in the file.
+    globals: Vec<usize>,
+    /// Functions being read, counting the one in progress.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn script(&mut self) -> Result<ObjRef, BytecodeError> {
+        let version = u16::from_le_bytes(self.take(2, "header")?.try_into().unwrap());
+        if version != VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+
+        let count = self.u32("globals")?;
+        for _ in 0..count {
+            let name = self.string("globals")?;
+            let name = self.vm.intern(name);
+            self.vm.stack.push(Value::obj(name));
+            let slot = self.vm.declare_global(name);
+            self.globals.push(slot);
+        }
+
+        let script = self.function()?;
+        let function = self.vm.heap.function(script);
+        if function.arity != 0 || function.upvalue_count != 0 {
+            return Err(BytecodeError::InvalidScript);
+        }
+        if self.pos != self.bytes.len() {
+            return Err(BytecodeError::TrailingBytes);
+        }
+        Ok(script)
+    }
+
+    fn take(&mut self, len: usize, section: &'static str) -> Result<&'a [u8], BytecodeError> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.bytes.len());
+        let end = end.ok_or(BytecodeError::Truncated(section))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self, section: &'static str) -> Result<u8, BytecodeError> {
+        Ok(self.take(1, section)?[0])
+    }
+
+    fn u32(&mut self, section: &'static str) -> Result<usize, BytecodeError> {
+        let bytes = self.take(4, section)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self, section: &'static str) -> Result<&'a str, BytecodeError> {
+        let len = self.u32(section)?;
+        let bytes = self.take(len, section)?;
+        std::str::from_utf8(bytes).map_err(|_| BytecodeError::InvalidString)
+    }
+
+    fn function(&mut self) -> Result<ObjRef, BytecodeError> {
+        if self.depth == MAX_FUNCTION_DEPTH {
+            return Err(BytecodeError::TooDeeplyNested);
+        }
+        self.depth += 1;
+        let function = self.function_body();
+        self.depth -= 1;
+        function
+    }
+
+    fn function_body(&mut self) -> Result<ObjRef, BytecodeError> {
+        let mut function = Function::new();
+        function.name = self.string("function")?.to_string();
+        function.arity = self.u32("function")?;
+        function.upvalue_count = self.u32("function")?;
+
+        let len = self.u32("code")?;
+        function.chunk.code = self.take(len, "code")?.to_vec();
+        function.chunk.lines = self.lines(len)?;
+
+        let count = self.u32("constants")?;
+        for _ in 0..count {
+            let constant = self.constant()?;
+            function.chunk.constants.push(constant);
+        }
+
+        verify(&function, self.vm.heap(), self.globals.len()).map_err(BytecodeError::Invalid)?;
+        self.remap_globals(&mut function.chunk)?;
+        let function = self.vm.alloc(Obj::Function(function));
+        self.vm.stack.push(Value::obj(function));
+        Ok(function)
+    }
+
+    /// Reads the line table of `code_len` bytes of code. Run lengths are
+    /// checked before expanding, so a forged run cannot exhaust memory.
+    fn lines(&mut self, code_len: usize) -> Result<Vec<usize>, BytecodeError> {
+        let mut lines = Vec::new();
+        let runs = self.u32("line table")?;
+        for _ in 0..runs {
+            let line = self.u32("line table")?;
+            let len = self.u32("line table")?;
+            if len > code_len - lines.len() {
+                return Err(BytecodeError::LineTableTooLong);
+            }
+            lines.extend(std::iter::repeat_n(line, len));
+        }
+        Ok(lines)
+    }
+
+    fn constant(&mut self) -> Result<Value, BytecodeError> {
+        match self.u8("constants")? {
+            TAG_NUMBER => {
+                let bytes = self.take(8, "constants")?;
+                Ok(Value::number(f64::from_le_bytes(bytes.try_into().unwrap())))
+            }
+            TAG_STRING => {
+                let s = self.string("constants")?;
+                let r = self.vm.intern(s);
+                self.vm.stack.push(Value::obj(r));
+                Ok(Value::obj(r))
+            }
+            TAG_FUNCTION => Ok(Value::obj(self.function()?)),
+            tag => Err(BytecodeError::InvalidConstant(tag)),
+        }
+    }
+
+    /// Rewrites global operands from the file's slots to the loading VM's.
+    fn remap_globals(&self, chunk: &mut Chunk) -> Result<(), BytecodeError> {
+        if self.globals.iter().enumerate().all(|(i, &slot)| i == slot) {
+            return Ok(());
+        }
+
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let op = chunk.opcode(offset);
+            let is_global = matches!(
+                op,
+                OpCode::OpDefineGlobal
+                    | OpCode::OpDefineGlobalLong
+                    | OpCode::OpGetGlobal
+                    | OpCode::OpGetGlobalLong
+                    | OpCode::OpSetGlobal
+                    | OpCode::OpSetGlobalLong
+            );
+            if is_global {
+                let (slot, _) = chunk.read_index(offset);
+                let mapped = *self.globals.get(slot).ok_or(BytecodeError::UnknownGlobal(slot))?;
+                let max = if op.is_long() { MAX_LONG_OPERAND } else { u8::MAX as usize };
+                if mapped > max {
+                    let name = self.vm.global_name(mapped).unwrap_or("?").to_string();
+                    return Err(BytecodeError::GlobalOutOfRange(name));
+                }
+
+                if op.is_long() {
+                    chunk.code[offset + 1] = (mapped >> 16) as u8;
+                    chunk.code[offset + 2] = (mapped >> 8) as u8;
+                    chunk.code[offset + 3] = mapped as u8;
+                } else {
+                    chunk.code[offset + 1] = mapped as u8;
+                }
+            }
+            offset += chunk.instruction_len(offset, self.vm.heap());
+        }
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
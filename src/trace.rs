@@ -0,0 +1,75 @@
+//! Instruction-level execution tracing, enabled with [`VM::set_tracer`].
+//!
+//! Before each instruction the tracer writes the stack followed by the
+//! disassembled instruction. Filters narrow the output to some functions or
+//! a range of source lines. The whole module is only compiled with the
+//! `trace` feature, so builds without it pay nothing per instruction.
+//!
+//! [`VM::set_tracer`]: crate::VM::set_tracer
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// Where and what to trace.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    /// Names of the functions to trace; empty traces them all. The
+    /// top-level script is called `<script>`.
+    functions: Vec<String>,
+    lines: Option<RangeInclusive<usize>>,
+}
+
+impl Tracer {
+    /// Traces every instruction into `out`.
+    pub fn new(out: impl Write + 'static) -> Self {
+        Tracer { out: Box::new(out), functions: Vec::new(), lines: None }
+    }
+
+    pub fn stderr() -> Self {
+        Tracer::new(io::stderr())
+    }
+
+    /// Traces into the file at `path`, replacing its contents.
+    pub fn file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Tracer::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Only traces instructions in the function `name`. May be repeated to
+    /// trace several functions.
+    pub fn function(mut self, name: impl Into<String>) -> Self {
+        self.functions.push(name.into());
+        self
+    }
+
+    /// Only traces instructions compiled from source lines in `lines`.
+    pub fn lines(mut self, lines: RangeInclusive<usize>) -> Self {
+        self.lines = Some(lines);
+        self
+    }
+
+    /// Whether an instruction on `line` of `function` should be traced.
+    pub(crate) fn wants(&self, function: &str, line: usize) -> bool {
+        let function = if function.is_empty() { "<script>" } else { function };
+        let function_ok = self.functions.is_empty() || self.functions.iter().any(|f| f == function);
+        let line_ok = self.lines.as_ref().is_none_or(|lines| lines.contains(&line));
+        function_ok && line_ok
+    }
+
+    /// Writes one traced instruction. A failing sink must not abort the
+    /// program being traced, so write errors are ignored.
+    pub(crate) fn write(&mut self, text: &str) {
+        let _ = self.out.write_all(text.as_bytes());
+    }
+
+    pub(crate) fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
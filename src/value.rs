@@ -1,46 +1,308 @@
 use std::fmt;
 use std::fmt::Formatter;
-use crate::function::Function;
+use crate::heap::{Heap, Obj, ObjRef};
 
 static ERR_MARGIN: f64 = f64::EPSILON;
-#[derive(Clone, Debug)]
-pub enum Value {
+
+/// A Lox value: a number, a boolean, `nil` or a handle to a heap object.
+///
+/// By default this is a tagged enum. With the `nan-boxing` feature every value
+/// is packed into a single `u64` instead, using the unused payload bits of
+/// quiet NaNs for the non-number cases. Both representations expose the same
+/// API; use [`kind`](Value::kind) to match on a value.
+#[derive(Clone, Copy)]
+pub struct Value(Repr);
+
+/// A [`Value`] decoded for matching.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueKind {
     Bool(bool),
     Nil,
     Number(f64),
-    ObjString(String),
-    Function(Function)
+    Obj(ObjRef),
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+type Repr = ValueKind;
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub const NIL: Value = Value(ValueKind::Nil);
+
+    #[inline]
+    pub fn number(n: f64) -> Value {
+        Value(ValueKind::Number(n))
+    }
+
+    #[inline]
+    pub fn bool(b: bool) -> Value {
+        Value(ValueKind::Bool(b))
+    }
+
+    #[inline]
+    pub fn obj(r: ObjRef) -> Value {
+        Value(ValueKind::Obj(r))
+    }
+
+    #[inline]
+    pub fn kind(self) -> ValueKind {
+        self.0
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+type Repr = u64;
+
+/// Bits set in every non-number value: the exponent, the quiet bit and one
+/// more so that no NaN produced by arithmetic is mistaken for a tagged value.
+#[cfg(feature = "nan-boxing")]
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+/// Set, together with [`QNAN`], for object handles. The heap index lives in
+/// the low 48 bits.
+#[cfg(feature = "nan-boxing")]
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const TAG_NIL: u64 = 1;
+#[cfg(feature = "nan-boxing")]
+const TAG_FALSE: u64 = 2;
+#[cfg(feature = "nan-boxing")]
+const TAG_TRUE: u64 = 3;
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    pub const NIL: Value = Value(QNAN | TAG_NIL);
+
+    #[inline]
+    pub fn number(n: f64) -> Value {
+        // Canonicalise NaNs so their payload can never look like a tag.
+        let n = if n.is_nan() { f64::NAN } else { n };
+        Value(n.to_bits())
+    }
+
+    #[inline]
+    pub fn bool(b: bool) -> Value {
+        Value(QNAN | if b { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    #[inline]
+    pub fn obj(r: ObjRef) -> Value {
+        Value(SIGN_BIT | QNAN | r.index() as u64)
+    }
+
+    #[inline]
+    pub fn kind(self) -> ValueKind {
+        let bits = self.0;
+        if bits & QNAN != QNAN {
+            ValueKind::Number(f64::from_bits(bits))
+        } else if bits & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN {
+            ValueKind::Obj(ObjRef::new((bits & !(SIGN_BIT | QNAN)) as usize))
+        } else {
+            match bits & !QNAN {
+                TAG_NIL => ValueKind::Nil,
+                TAG_FALSE => ValueKind::Bool(false),
+                TAG_TRUE => ValueKind::Bool(true),
+                _ => unreachable!("Invalid value tag {:#x}", bits),
+            }
+        }
+    }
+}
+
+impl Value {
+    #[inline]
+    pub fn is_nil(self) -> bool {
+        self.kind() == ValueKind::Nil
+    }
+
+    #[inline]
+    pub fn as_bool(self) -> Option<bool> {
+        match self.kind() {
+            ValueKind::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn as_number(self) -> Option<f64> {
+        match self.kind() {
+            ValueKind::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn as_obj(self) -> Option<ObjRef> {
+        match self.kind() {
+            ValueKind::Obj(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    /// Name of the value's type as shown in error messages.
+    pub fn type_name(&self, heap: &Heap) -> &'static str {
+        match self.kind() {
+            ValueKind::Bool(_) => "boolean",
+            ValueKind::Nil => "nil",
+            ValueKind::Number(_) => "number",
+            ValueKind::Obj(r) => match heap.get(r) {
+                Obj::String(_) => "string",
+                Obj::Function(_)
+                | Obj::Closure(_)
+                | Obj::BoundMethod(_)
+                | Obj::Native(_) => "function",
+                Obj::Class(_) => "class",
+                Obj::Instance(_) => "instance",
+                Obj::Upvalue(_) => "upvalue",
+            },
+        }
+    }
+
+    /// Pairs the value with the heap its objects live in, for formatting
+    /// the way Lox's `print` shows it.
+    pub fn display(self, heap: &Heap) -> ValueDisplay<'_> {
+        ValueDisplay { value: self, heap }
+    }
+
+    /// Like [`display`](Value::display), but quotes and escapes strings so
+    /// they can be told apart from other values. Used by the tracer and
+    /// the REPL.
+    pub fn repr(self, heap: &Heap) -> ValueRepr<'_> {
+        ValueRepr { value: self, heap }
+    }
 }
 
+/// Strings are interned, so objects of every type compare by handle.
 pub fn values_equal(a: Value, b: Value) -> bool {
-    match (a, b) {
-        (Value::Number(a), Value::Number(b)) => (a - b).abs() < ERR_MARGIN,
-        (Value::Bool(a), Value::Bool(b)) => a == b,
-        (Value::Nil, Value::Nil) => true,
-        (Value::ObjString(str1), Value::ObjString(str2)) => str1 == str2,
-        (Value::Function(a), Value::Function(b)) => a == b,
+    match (a.kind(), b.kind()) {
+        (ValueKind::Number(a), ValueKind::Number(b)) => (a - b).abs() < ERR_MARGIN,
+        (ValueKind::Bool(a), ValueKind::Bool(b)) => a == b,
+        (ValueKind::Nil, ValueKind::Nil) => true,
+        (ValueKind::Obj(a), ValueKind::Obj(b)) => a == b,
         _ => false,
     }
 }
 
-pub fn print_value(val: &Value) {
-    match val {
-        Value::Bool(n)  => print!("bool: {:?}", n),
-        Value::Nil              => print!("nil"),
-        Value::Number(n) => print!("number: {:?}", n),
-        Value::ObjString(str) => print!("Objstring: {:?}", str),
-        Value::Function(fun) => print!("ObjFunction: {}", "(fun)"),
+pub struct ValueDisplay<'h> {
+    value: Value,
+    heap: &'h Heap,
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let heap = self.heap;
+        match self.value.kind() {
+            ValueKind::Number(val) => write_number(f, val),
+            ValueKind::Bool(val) => write!(f, "{}", val),
+            ValueKind::Nil => write!(f, "nil"),
+            ValueKind::Obj(r) => match heap.get(r) {
+                Obj::String(s) => write!(f, "{}", s),
+                Obj::Function(func) => write!(f, "{}", func),
+                Obj::Closure(closure) => write!(f, "{}", heap.function(closure.function)),
+                Obj::Upvalue(_) => write!(f, "upvalue"),
+                Obj::Class(class) => write!(f, "{}", class),
+                Obj::Instance(instance) => {
+                    write!(f, "{} instance", heap.class(instance.class))
+                }
+                Obj::BoundMethod(bound) => {
+                    let closure = heap.closure(bound.method);
+                    write!(f, "{}", heap.function(closure.function))
+                }
+                Obj::Native(native) => write!(f, "{}", native),
+            },
+        }
     }
 }
 
-impl fmt::Display for Value {
+/// Significant digits in a printed number, as with C's `%g`.
+const PRECISION: i32 = 6;
+
+/// Writes `n` the way clox's `printf("%g", n)` does: six significant
+/// digits, trailing zeros dropped, and an exponent once the number is
+/// below 1e-4 or at least 1e6.
+fn write_number(f: &mut Formatter<'_>, n: f64) -> fmt::Result {
+    if n.is_nan() {
+        return write!(f, "nan");
+    }
+    if n.is_infinite() {
+        return write!(f, "{}", if n < 0.0 { "-inf" } else { "inf" });
+    }
+    if n == 0.0 {
+        return write!(f, "{}", if n.is_sign_negative() { "-0" } else { "0" });
+    }
+
+    // Round to the printed precision first; that can carry into the next
+    // power of ten, as 999999.5 does.
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, n);
+    let (mantissa, exponent) = scientific.split_once('e').expect("Exponent in {:e}");
+    let exponent: i32 = exponent.parse().expect("Integer exponent");
+    if (-4..PRECISION).contains(&exponent) {
+        let fixed = format!("{:.*}", (PRECISION - 1 - exponent) as usize, n);
+        write!(f, "{}", trim_fraction(&fixed))
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        write!(f, "{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    }
+}
+
+/// Drops trailing zeros after the decimal point, and the point if nothing
+/// follows it.
+fn trim_fraction(digits: &str) -> &str {
+    if digits.contains('.') {
+        digits.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        digits
+    }
+}
+
+pub struct ValueRepr<'h> {
+    value: Value,
+    heap: &'h Heap,
+}
+
+impl fmt::Display for ValueRepr<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::Number(val) => write!(f, "{}", val),
-            Self::Bool(val) => write!(f, "{}", val),
-            Self::ObjString(s) => write!(f, "{}", s),
-            Self::Function(func) => write!(f, "{}", "(func)"),
-            Self::Nil => write!(f, "nil"),
+        match self.value.as_obj().map(|r| self.heap.get(r)) {
+            Some(Obj::String(s)) => write!(f, "{:?}", s),
+            _ => write!(f, "{}", self.value.display(self.heap)),
         }
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.kind().fmt(f)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::bool(b)
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::NIL
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    fn try_from(val: Value) -> Result<Self, Self::Error> {
+        val.as_number().ok_or_else(|| "Expected a number.".to_string())
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = String;
+
+    fn try_from(val: Value) -> Result<Self, Self::Error> {
+        val.as_bool().ok_or_else(|| "Expected a boolean.".to_string())
+    }
+}
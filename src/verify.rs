@@ -0,0 +1,395 @@
+//! Static checks over a function's bytecode, run before executing code that
+//! did not come straight from the compiler.
+//!
+//! Verification decodes every instruction, checks its operands against the
+//! chunk, then follows every path through the code to make sure the stack
+//! depth at each instruction is the same however it is reached. Code that
+//! passes can be run without the VM indexing out of bounds.
+
+use std::error::Error;
+use std::fmt;
+use crate::chunk::{Chunk, OpCode};
+use crate::function::Function;
+use crate::heap::{Heap, Obj};
+use crate::value::Value;
+
+/// Where and why a function failed verification.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyError {
+    /// Name of the offending function, empty for the top-level script.
+    pub function: String,
+    /// Offset of the offending instruction.
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyErrorKind {
+    InvalidOpcode(u8),
+    /// The instruction's operands run past the end of the code.
+    Truncated,
+    /// The line table does not have one entry per byte of code.
+    LineTableMismatch,
+    ConstantOutOfRange(usize),
+    /// A constant has the wrong type for the instruction using it.
+    WrongConstant { index: usize, expected: &'static str },
+    LocalOutOfRange(usize),
+    UpvalueOutOfRange(usize),
+    GlobalOutOfRange(usize),
+    /// A closure capture whose flags byte has unknown bits set.
+    InvalidCapture(u8),
+    JumpOutOfBounds,
+    /// The jump lands inside another instruction's operands.
+    JumpIntoInstruction(usize),
+    StackUnderflow,
+    /// Two paths reach the instruction with different stack depths.
+    StackMismatch { expected: usize, found: usize },
+    /// Execution can run past the last instruction.
+    FallsOffEnd,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.function.is_empty() { "<script>" } else { &self.function };
+        write!(f, "{} at offset {}: ", name, self.offset)?;
+        match &self.kind {
+            VerifyErrorKind::InvalidOpcode(byte) => write!(f, "invalid opcode {}.", byte),
+            VerifyErrorKind::Truncated => write!(f, "instruction is truncated."),
+            VerifyErrorKind::LineTableMismatch => write!(f, "line table does not match code."),
+            VerifyErrorKind::ConstantOutOfRange(index) => {
+                write!(f, "constant {} out of range.", index)
+            }
+            VerifyErrorKind::WrongConstant { index, expected } => {
+                write!(f, "constant {} is not a {}.", index, expected)
+            }
+            VerifyErrorKind::LocalOutOfRange(slot) => write!(f, "local {} out of range.", slot),
+            VerifyErrorKind::UpvalueOutOfRange(index) => {
+                write!(f, "upvalue {} out of range.", index)
+            }
+            VerifyErrorKind::GlobalOutOfRange(slot) => write!(f, "global {} out of range.", slot),
+            VerifyErrorKind::InvalidCapture(flags) => {
+                write!(f, "invalid capture flags {:#04x}.", flags)
+            }
+            VerifyErrorKind::JumpOutOfBounds => write!(f, "jump target out of bounds."),
+            VerifyErrorKind::JumpIntoInstruction(target) => {
+                write!(f, "jump target {} is not an instruction.", target)
+            }
+            VerifyErrorKind::StackUnderflow => write!(f, "stack underflow."),
+            VerifyErrorKind::StackMismatch { expected, found } => {
+                write!(f, "stack depth {} where {} was expected.", found, expected)
+            }
+            VerifyErrorKind::FallsOffEnd => write!(f, "execution runs past the end of the code."),
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
+/// Checks `function`'s bytecode against a VM with `globals` global slots.
+///
+/// Functions nested in the constant pool are only consulted for their
+/// upvalue counts; each must be verified on its own.
+pub fn verify(function: &Function, heap: &Heap, globals: usize) -> Result<(), VerifyError> {
+    let verifier = Verifier { function, chunk: &function.chunk, heap, globals };
+    let instructions = verifier.decode()?;
+    verifier.check_stack(&instructions)
+}
+
+/// A decoded instruction.
+struct Instruction {
+    op: OpCode,
+    /// The index, count or slot operand, if any.
+    operand: usize,
+    next: usize,
+    /// Destination of a jump or loop.
+    target: Option<usize>,
+    /// Stack slots captured by a closure.
+    captured_locals: Vec<usize>,
+}
+
+struct Verifier<'a> {
+    function: &'a Function,
+    chunk: &'a Chunk,
+    heap: &'a Heap,
+    globals: usize,
+}
+
+impl Verifier<'_> {
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError { function: self.function.name.clone(), offset, kind }
+    }
+
+    /// Decodes every instruction, indexed by the offset it starts at.
+    fn decode(&self) -> Result<Vec<Option<Instruction>>, VerifyError> {
+        let code = &self.chunk.code;
+        if self.chunk.lines.len() != code.len() {
+            return Err(self.error(0, VerifyErrorKind::LineTableMismatch));
+        }
+
+        let mut instructions: Vec<Option<Instruction>> = Vec::new();
+        instructions.resize_with(code.len(), || None);
+        let mut offset = 0;
+        while offset < code.len() {
+            let instruction = self.decode_instruction(offset)?;
+            let next = instruction.next;
+            instructions[offset] = Some(instruction);
+            offset = next;
+        }
+
+        for (offset, instruction) in instructions.iter().enumerate() {
+            let target = match instruction.as_ref().and_then(|i| i.target) {
+                Some(target) => target,
+                None => continue,
+            };
+            if target >= code.len() {
+                return Err(self.error(offset, VerifyErrorKind::JumpOutOfBounds));
+            }
+            if instructions[target].is_none() {
+                return Err(self.error(offset, VerifyErrorKind::JumpIntoInstruction(target)));
+            }
+        }
+        Ok(instructions)
+    }
+
+    fn decode_instruction(&self, offset: usize) -> Result<Instruction, VerifyError> {
+        let code = &self.chunk.code;
+        let op = OpCode::try_from(code[offset])
+            .map_err(|byte| self.error(offset, VerifyErrorKind::InvalidOpcode(byte)))?;
+        let mut next = offset + 1 + op.operand_len();
+        if next > code.len() {
+            return Err(self.error(offset, VerifyErrorKind::Truncated));
+        }
+
+        let operand = match op.operand_len() {
+            0 => 0,
+            1 => code[offset + 1] as usize,
+            2 => (code[offset + 1] as usize) << 8 | code[offset + 2] as usize,
+            _ => self.chunk.read_u24(offset + 1),
+        };
+        let mut instruction = Instruction {
+            op,
+            operand,
+            next,
+            target: None,
+            captured_locals: Vec::new(),
+        };
+
+        match op {
+            OpCode::OpConstant | OpCode::OpConstantLong => {
+                self.constant(offset, operand)?;
+            }
+            OpCode::OpGetProperty
+            | OpCode::OpSetProperty
+            | OpCode::OpGetSuper
+            | OpCode::OpClass
+            | OpCode::OpMethod
+            | OpCode::OpGetPropertyLong
+            | OpCode::OpSetPropertyLong
+            | OpCode::OpGetSuperLong
+            | OpCode::OpClassLong
+            | OpCode::OpMethodLong => {
+                let constant = self.constant(offset, operand)?;
+                if !matches!(self.object(constant), Some(Obj::String(_))) {
+                    let kind = VerifyErrorKind::WrongConstant { index: operand, expected: "string" };
+                    return Err(self.error(offset, kind));
+                }
+            }
+            OpCode::OpDefineGlobal
+            | OpCode::OpDefineGlobalLong
+            | OpCode::OpGetGlobal
+            | OpCode::OpGetGlobalLong
+            | OpCode::OpSetGlobal
+            | OpCode::OpSetGlobalLong
+                if operand >= self.globals =>
+            {
+                return Err(self.error(offset, VerifyErrorKind::GlobalOutOfRange(operand)));
+            }
+            OpCode::OpGetUpvalue | OpCode::OpSetUpvalue
+                if operand >= self.function.upvalue_count =>
+            {
+                return Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange(operand)));
+            }
+            OpCode::OpJumpIfFalse
+            | OpCode::OpJumpIfFalseLong
+            | OpCode::OpJump
+            | OpCode::OpJumpLong => {
+                instruction.target = Some(next + operand);
+            }
+            OpCode::OpLoop | OpCode::OpLoopLong => {
+                let target = next.checked_sub(operand);
+                let target = target.ok_or_else(|| self.error(offset, VerifyErrorKind::JumpOutOfBounds))?;
+                instruction.target = Some(target);
+            }
+            OpCode::OpClosure | OpCode::OpClosureLong => {
+                let constant = self.constant(offset, operand)?;
+                let upvalue_count = match self.object(constant) {
+                    Some(Obj::Function(function)) => function.upvalue_count,
+                    _ => {
+                        let kind =
+                            VerifyErrorKind::WrongConstant { index: operand, expected: "function" };
+                        return Err(self.error(offset, kind));
+                    }
+                };
+                for _ in 0..upvalue_count {
+                    let flags = *code
+                        .get(next)
+                        .ok_or_else(|| self.error(offset, VerifyErrorKind::Truncated))?;
+                    if flags & !3 != 0 {
+                        return Err(self.error(offset, VerifyErrorKind::InvalidCapture(flags)));
+                    }
+                    let wide = flags & 2 != 0;
+                    let end = next + if wide { 4 } else { 2 };
+                    if end > code.len() {
+                        return Err(self.error(offset, VerifyErrorKind::Truncated));
+                    }
+                    let index = if wide {
+                        self.chunk.read_u24(next + 1)
+                    } else {
+                        code[next + 1] as usize
+                    };
+
+                    if flags & 1 == 1 {
+                        instruction.captured_locals.push(index);
+                    } else if index >= self.function.upvalue_count {
+                        return Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange(index)));
+                    }
+                    next = end;
+                }
+                instruction.next = next;
+            }
+            _ => {}
+        }
+        Ok(instruction)
+    }
+
+    fn constant(&self, offset: usize, index: usize) -> Result<Value, VerifyError> {
+        self.chunk
+            .get_constant(index)
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::ConstantOutOfRange(index)))
+    }
+
+    fn object(&self, constant: Value) -> Option<&Obj> {
+        constant.as_obj().map(|r| self.heap.get(r))
+    }
+
+    /// Walks every reachable path, tracking the number of values in the
+    /// frame. Slot zero holds the callee and the parameters follow it.
+    fn check_stack(&self, instructions: &[Option<Instruction>]) -> Result<(), VerifyError> {
+        let len = instructions.len();
+        if len == 0 {
+            return Err(self.error(0, VerifyErrorKind::FallsOffEnd));
+        }
+
+        let mut depths: Vec<Option<usize>> = vec![None; len];
+        let mut pending = vec![(0, self.function.arity + 1)];
+        while let Some((offset, depth)) = pending.pop() {
+            match depths[offset] {
+                Some(expected) if expected == depth => continue,
+                Some(expected) => {
+                    let kind = VerifyErrorKind::StackMismatch { expected, found: depth };
+                    return Err(self.error(offset, kind));
+                }
+                None => depths[offset] = Some(depth),
+            }
+
+            let instruction = instructions[offset].as_ref().expect("Decoded instruction");
+            if let Some(slot) = self.local_slot(instruction) {
+                if slot >= depth {
+                    return Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(slot)));
+                }
+            }
+
+            let (pops, pushes) = stack_effect(instruction);
+            if depth < pops {
+                return Err(self.error(offset, VerifyErrorKind::StackUnderflow));
+            }
+            let after = depth - pops + pushes;
+
+            let falls_through = !matches!(
+                instruction.op,
+                OpCode::OpReturn
+                    | OpCode::OpJump
+                    | OpCode::OpJumpLong
+                    | OpCode::OpLoop
+                    | OpCode::OpLoopLong
+            );
+            if falls_through {
+                if instruction.next >= len {
+                    return Err(self.error(offset, VerifyErrorKind::FallsOffEnd));
+                }
+                pending.push((instruction.next, after));
+            }
+            if let Some(target) = instruction.target {
+                pending.push((target, after));
+            }
+        }
+        Ok(())
+    }
+
+    /// The highest frame slot the instruction reads or writes, if any.
+    fn local_slot(&self, instruction: &Instruction) -> Option<usize> {
+        match instruction.op {
+            OpCode::OpGetLocal
+            | OpCode::OpGetLocalLong
+            | OpCode::OpSetLocal
+            | OpCode::OpSetLocalLong => Some(instruction.operand),
+            OpCode::OpClosure | OpCode::OpClosureLong => {
+                instruction.captured_locals.iter().copied().max()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How many values the instruction pops, and how many it then pushes.
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    match instruction.op {
+        OpCode::OpConstant
+        | OpCode::OpConstantLong
+        | OpCode::OpNil
+        | OpCode::OpTrue
+        | OpCode::OpFalse
+        | OpCode::OpGetLocal
+        | OpCode::OpGetLocalLong
+        | OpCode::OpGetGlobal
+        | OpCode::OpGetGlobalLong
+        | OpCode::OpGetUpvalue
+        | OpCode::OpClosure
+        | OpCode::OpClosureLong
+        | OpCode::OpClass
+        | OpCode::OpClassLong => (0, 1),
+        OpCode::OpPop
+        | OpCode::OpDefineGlobal
+        | OpCode::OpDefineGlobalLong
+        | OpCode::OpPrint
+        | OpCode::OpCloseUpvalue
+        | OpCode::OpReturn => (1, 0),
+        OpCode::OpSetLocal
+        | OpCode::OpSetLocalLong
+        | OpCode::OpSetGlobal
+        | OpCode::OpSetGlobalLong
+        | OpCode::OpSetUpvalue
+        | OpCode::OpGetProperty
+        | OpCode::OpGetPropertyLong
+        | OpCode::OpNot
+        | OpCode::OpNegate
+        | OpCode::OpJumpIfFalse
+        | OpCode::OpJumpIfFalseLong => (1, 1),
+        OpCode::OpSetProperty
+        | OpCode::OpSetPropertyLong
+        | OpCode::OpGetSuper
+        | OpCode::OpGetSuperLong
+        | OpCode::OpEqual
+        | OpCode::OpGreater
+        | OpCode::OpLess
+        | OpCode::OpAdd
+        | OpCode::OpSubtract
+        | OpCode::OpMultiply
+        | OpCode::OpDivide
+        | OpCode::OpInherit
+        | OpCode::OpMethod
+        | OpCode::OpMethodLong => (2, 1),
+        OpCode::OpJump | OpCode::OpJumpLong | OpCode::OpLoop | OpCode::OpLoopLong => (0, 0),
+        OpCode::OpCall => (instruction.operand + 1, 1),
+    }
+}
@@ -1,22 +1,74 @@
-use std::rc::Rc;
-use std::collections::hash_map::Entry;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::rc::Rc;
 use crate::chunk::{Chunk, OpCode};
-use crate::value::{print_value, Value, values_equal};
+use crate::value::{Value, ValueKind, values_equal};
 use crate::compiler::Parser;
+use crate::error::{Diagnostic, ErrorKind, LoxError, TraceFrame};
+use crate::heap::{Heap, Obj, ObjRef};
+use crate::natives;
+use crate::disassembler;
+use crate::serialize;
+use crate::function::{Closure, NativeFn, NativeFunction, Upvalue};
+use crate::class::{BoundMethod, Class, Instance};
+#[cfg(feature = "trace")]
+use crate::trace::Tracer;
 
-pub struct VM {
-    pub chunk: Rc<Chunk>,
+const FRAMES_MAX: usize = 64;
+
+pub struct CallFrame {
+    pub closure: ObjRef,
     pub ip: usize,
-    pub stack: Vec<Value>,
-    pub globals: HashMap<String, Value>,
+    /// Index of the frame's first stack slot, which holds the callee itself.
+    pub slots: usize,
+}
+
+impl CallFrame {
+    pub fn new(closure: ObjRef, slots: usize) -> Self {
+        Self {
+            closure,
+            ip: 0,
+            slots,
+        }
+    }
 }
 
-#[derive(PartialEq, Debug)]
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError
+/// The Lox virtual machine. Globals persist across calls to
+/// [`interpret`](VM::interpret), so one VM can run a whole REPL session.
+pub struct VM {
+    pub(crate) frames: Vec<CallFrame>,
+    pub(crate) stack: Vec<Value>,
+    /// Global variables by slot, as assigned by the compiler. A slot holds
+    /// `None` until the variable's declaration has run.
+    pub(crate) globals: Vec<Option<Value>>,
+    /// Interned name of each global slot.
+    global_names: Vec<ObjRef>,
+    global_slots: HashMap<ObjRef, usize>,
+    /// Upvalues still pointing into the stack, ordered by slot.
+    open_upvalues: Vec<ObjRef>,
+    /// Objects handed out by [`VM::string`] that nothing else may reference
+    /// yet. Kept alive until the native that made them returns or the next
+    /// script starts.
+    temp_roots: Vec<ObjRef>,
+    /// Frame and stack depth where the innermost running script began.
+    /// Above zero while a native re-enters the VM; that script returns,
+    /// or unwinds on an error, down to here and no further.
+    base_frame: usize,
+    base_slot: usize,
+    pub(crate) heap: Heap,
+    /// The interned name `init`, looked up whenever a class is called.
+    init_string: ObjRef,
+    /// Where `print` writes. Standard output unless replaced.
+    output: Box<dyn Write>,
+    /// Where compile and runtime errors are reported, besides being
+    /// returned. Discarded unless replaced.
+    diagnostics: Box<dyn Write>,
+    /// Input for natives that read.
+    input: Box<dyn BufRead>,
+    #[cfg(feature = "trace")]
+    tracer: Option<Tracer>,
 }
 
 impl Default for VM {
@@ -28,243 +80,976 @@ impl Default for VM {
 impl VM {
 
     pub fn new() -> Self {
-        Self {
-            chunk: Rc::new(Chunk::new()),
-            ip: 0,
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let mut vm = Self {
+            frames: Vec::with_capacity(FRAMES_MAX),
             stack: Vec::new(),
-            globals: HashMap::new(),
+            globals: Vec::new(),
+            global_names: Vec::new(),
+            global_slots: HashMap::new(),
+            open_upvalues: Vec::new(),
+            temp_roots: Vec::new(),
+            base_frame: 0,
+            base_slot: 0,
+            heap,
+            init_string,
+            output: Box::new(io::stdout()),
+            diagnostics: Box::new(io::sink()),
+            input: Box::new(io::BufReader::new(io::stdin())),
+            #[cfg(feature = "trace")]
+            tracer: None,
+        };
+        vm.define_native("clock", 0, natives::clock);
+        vm
+    }
+
+    /// Returns the current value of the global `name`, if it is defined.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let name = self.heap.find_string(name)?;
+        let slot = *self.global_slots.get(&name)?;
+        self.globals[slot]
+    }
+
+    /// Defines or overwrites the global `name`.
+    pub fn set_global(&mut self, name: &str, val: impl Into<Value>) {
+        // Keep the value rooted while its name is interned.
+        self.stack.push(val.into());
+        let name = self.intern(name);
+        let val = self.stack.pop().expect("Empty stack");
+        let slot = self.declare_global(name);
+        self.globals[slot] = Some(val);
+    }
+
+    /// Exposes a host function to scripts as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = self.alloc(Obj::Native(NativeFunction::new(name, arity, function)));
+        self.set_global(name, Value::obj(native));
+    }
+
+    /// Returns the slot of the global `name`, reserving a new, uninitialized
+    /// one if the name has not been seen before. Slots are never reused, so
+    /// code compiled earlier in a session stays valid.
+    pub(crate) fn declare_global(&mut self, name: ObjRef) -> usize {
+        if let Some(&slot) = self.global_slots.get(&name) {
+            return slot;
         }
+
+        let slot = self.globals.len();
+        self.globals.push(None);
+        self.global_names.push(name);
+        self.global_slots.insert(name, slot);
+        slot
+    }
+
+    /// Name of the global variable in `slot`.
+    pub fn global_name(&self, slot: usize) -> Option<&str> {
+        let name = *self.global_names.get(slot)?;
+        Some(self.heap.string(name))
     }
 
-    pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let mut parser = Parser::new(source);
+    /// Every defined global with its value, in the order the names were
+    /// first seen.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, Value)> + '_ {
+        self.globals.iter().enumerate().filter_map(|(slot, value)| {
+            Some((self.heap.string(self.global_names[slot]), (*value)?))
+        })
+    }
+
+    /// Creates a Lox string, for natives and hosts to return or store. The
+    /// string survives garbage collection until the native creating it
+    /// returns or, outside a native, until the next script runs.
+    pub fn string(&mut self, s: &str) -> Value {
+        let r = self.intern(s);
+        self.temp_roots.push(r);
+        Value::obj(r)
+    }
 
-        if parser.compile().is_none() {
-            return InterpretResult::CompileError;
+    /// The contents of `value` if it is a string.
+    pub fn as_str(&self, value: Value) -> Option<&str> {
+        match self.heap.get(value.as_obj()?) {
+            Obj::String(s) => Some(s),
+            _ => None,
         }
+    }
+
+    /// The heap holding every object created by this VM.
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Sets the heap size, in bytes, below which no collection is triggered.
+    pub fn set_gc_threshold(&mut self, bytes: usize) {
+        self.heap.set_threshold(bytes);
+    }
+
+    /// Sets how many times its surviving size the heap may grow before the
+    /// next collection.
+    pub fn set_gc_grow_factor(&mut self, factor: usize) {
+        self.heap.grow_factor = factor.max(1);
+    }
+
+    /// When enabled, collects garbage before every allocation.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.stress = stress;
+    }
+
+    /// Sends program output to `output`, returning the previous sink.
+    pub fn set_output(&mut self, output: Box<dyn Write>) -> Box<dyn Write> {
+        std::mem::replace(&mut self.output, output)
+    }
+
+    /// Also writes every error returned to `diagnostics`, returning the
+    /// previous sink. Nothing is written by default.
+    pub fn set_diagnostics(&mut self, diagnostics: Box<dyn Write>) -> Box<dyn Write> {
+        std::mem::replace(&mut self.diagnostics, diagnostics)
+    }
 
-        self.chunk = Rc::new(parser.compiler.function.chunk);
-        self.ip = 0; // or self.chunk.code?
+    /// Reads input from `input`, returning the previous reader.
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) -> Box<dyn BufRead> {
+        std::mem::replace(&mut self.input, input)
+    }
+
+    /// The program output sink, for natives that print.
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut *self.output
+    }
+
+    /// The input reader, for natives that read.
+    pub fn input(&mut self) -> &mut dyn BufRead {
+        &mut *self.input
+    }
+
+    /// Traces every instruction the VM runs into `tracer`, or stops tracing
+    /// when given `None`. Returns the previous tracer.
+    #[cfg(feature = "trace")]
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// Frees every object unreachable from the VM's roots: the stack, the
+    /// globals, the active call frames and the open upvalues. Returns the
+    /// number of objects freed.
+    pub fn collect_garbage(&mut self) -> usize {
+        for &val in &self.stack {
+            self.heap.mark_value(val);
+        }
+        for &val in self.globals.iter().flatten() {
+            self.heap.mark_value(val);
+        }
+        for &name in &self.global_names {
+            self.heap.mark_object(name);
+        }
+        self.heap.mark_object(self.init_string);
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+        for &r in &self.temp_roots {
+            self.heap.mark_object(r);
+        }
+
+        self.heap.trace();
+        self.heap.sweep()
+    }
+
+    /// Charges the heap for whatever `r` grew by since it measured `size`
+    /// bytes, collecting if that crosses the threshold. `r` and everything
+    /// it refers to must be reachable from the roots.
+    fn account_growth(&mut self, r: ObjRef, size: usize) {
+        let grown = self.heap.size_of(r).saturating_sub(size);
+        if grown > 0 {
+            self.heap.account(grown);
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+        }
+    }
+
+    /// Moves `obj` into the heap, collecting first if the heap has grown past
+    /// its threshold. The new object itself is always kept alive.
+    pub(crate) fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let r = self.heap.alloc(obj);
+        if self.heap.should_collect() {
+            self.heap.mark_object(r);
+            self.collect_garbage();
+        }
+        r
+    }
+
+    /// Returns the interned string object for `s`, collecting first if the
+    /// heap has grown past its threshold.
+    pub(crate) fn intern(&mut self, s: &str) -> ObjRef {
+        let r = self.heap.intern(s);
+        if self.heap.should_collect() {
+            self.heap.mark_object(r);
+            self.collect_garbage();
+        }
+        r
+    }
+
+    /// Compiles and runs `source` as a top-level script, returning the
+    /// script's result or every diagnostic produced along the way.
+    pub fn interpret(&mut self, source: &str) -> Result<Value, LoxError> {
+        let result = Parser::new(source, self)
+            .compile()
+            .map_err(LoxError::compile)
+            .and_then(|function| {
+                let function = self.alloc(Obj::Function(function));
+                self.run_script(function)
+            });
+        self.report(result)
+    }
+
+    /// Compiles and runs one REPL entry. Returns the value of the entry's
+    /// final expression statement, or `None` if it does not end with one.
+    pub fn interpret_repl(&mut self, source: &str) -> Result<Option<Value>, LoxError> {
+        let result = Parser::new(source, self)
+            .compile_repl()
+            .map_err(LoxError::compile)
+            .and_then(|(function, echoed)| {
+                let function = self.alloc(Obj::Function(function));
+                let value = self.run_script(function)?;
+                Ok(echoed.then_some(value))
+            });
+        self.report(result)
+    }
+
+    /// Runs `source` with program output and diagnostics captured instead
+    /// of written to the configured sinks. Returns the result along with
+    /// everything printed and reported.
+    pub fn interpret_capturing(&mut self, source: &str) -> (Result<Value, LoxError>, String, String) {
+        let output = SharedBuffer::default();
+        let diagnostics = SharedBuffer::default();
+        let old_output = self.set_output(Box::new(output.clone()));
+        let old_diagnostics = self.set_diagnostics(Box::new(diagnostics.clone()));
+
+        let result = self.interpret(source);
+
+        self.set_output(old_output);
+        self.set_diagnostics(old_diagnostics);
+        (result, output.text(), diagnostics.text())
+    }
+
+    /// Writes any error in `result` to the diagnostics sink.
+    fn report<T>(&mut self, result: Result<T, LoxError>) -> Result<T, LoxError> {
+        if let Err(err) = &result {
+            let _ = writeln!(self.diagnostics, "{}", err);
+            let _ = self.diagnostics.flush();
+        }
+        result
+    }
+
+    /// Compiles `source` into the `.loxc` format without running it. The
+    /// script's globals are declared in this VM as a side effect.
+    pub fn compile(&mut self, source: &str) -> Result<Vec<u8>, LoxError> {
+        let result = Parser::new(source, self)
+            .compile()
+            .map_err(LoxError::compile)
+            .and_then(|function| {
+                serialize::serialize(self, &function).map_err(LoxError::bytecode)
+            });
+        self.report(result)
+    }
+
+    /// Compiles `source` and lists the bytecode of the script and every
+    /// function declared in it.
+    pub fn disassemble(&mut self, source: &str) -> Result<String, LoxError> {
+        let result = Parser::new(source, self).compile().map_err(LoxError::compile);
+        let function = self.report(result)?;
+        let mut listing = String::new();
+        disassembler::disassemble_function(&function, self, &mut listing)
+            .expect("Writing to a String cannot fail");
+        Ok(listing)
+    }
+
+    /// Loads a script produced by [`compile`](VM::compile) and runs it.
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> Result<Value, LoxError> {
+        let result = serialize::deserialize(self, bytes)
+            .map_err(LoxError::bytecode)
+            .and_then(|function| self.run_script(function));
+        self.report(result)
+    }
+
+    /// Runs a compiled script on top of whatever is already executing, so
+    /// natives can call back into the VM.
+    fn run_script(&mut self, function: ObjRef) -> Result<Value, LoxError> {
+        let temp_roots = mem::take(&mut self.temp_roots);
+        let base = (self.base_frame, self.base_slot);
+        self.base_frame = self.frames.len();
+        self.base_slot = self.stack.len();
+
+        let result = self.call_script(function);
+
+        self.base_frame = base.0;
+        self.base_slot = base.1;
+        self.temp_roots = temp_roots;
+        result
+    }
+
+    fn call_script(&mut self, function: ObjRef) -> Result<Value, LoxError> {
+        self.stack.push(Value::obj(function));
+        let closure = self.alloc(Obj::Closure(Closure::new(function, Vec::new())));
+        self.stack.pop();
+        self.stack.push(Value::obj(closure));
+        if let Err(msg) = self.call(closure, 0) {
+            return Err(self.runtime_error(&msg));
+        }
 
         self.run()
     }
 
-    pub fn run(&mut self) -> InterpretResult {
+    /// Runs until the current script's frame returns. Every runtime error
+    /// funnels through here, so the VM is always left with a clean stack.
+    pub fn run(&mut self) -> Result<Value, LoxError> {
+        let result = loop {
+            match self.step() {
+                Ok(None) => (),
+                Ok(Some(result)) => break Ok(result),
+                Err(msg) => break Err(self.runtime_error(&msg)),
+            }
+        };
+
+        let _ = self.output.flush();
+        #[cfg(feature = "trace")]
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush();
+        }
+        result
+    }
+
+    /// Executes one instruction, yielding the script's result once its
+    /// frame returns.
+    fn step(&mut self) -> Result<Option<Value>, String> {
+        #[cfg(feature = "trace")]
+        if self.tracer.is_some() {
+            self.trace_instruction();
+        }
 
-        loop {
-           self.debug_trace_execution();
+        let opcode = self.read_opcode()?;
 
-            let opcode: OpCode = self.read_opcode();
+        match opcode {
+            OpCode::OpConstant | OpCode::OpConstantLong => {
+                let idx = self.read_index(opcode);
+                let constant = self.constant(idx)?;
+                self.stack.push(constant);
+            },
 
-            match opcode {
-                OpCode::OpConstant => {
-                    let constant = self.read_constant().clone();
-                    self.stack.push(constant);
+            OpCode::OpNegate => match self.peek(0).kind() {
+                ValueKind::Number(val) => {
+                    let neg_val = -val;
+                    self.stack.pop();
+                    self.stack.push(Value::number(neg_val));
                 },
+                _ => {
+                    let ty = self.peek(0).type_name(&self.heap);
+                    return Err(format!("Operand must be a number, got {}.", ty));
+                }
+            },
 
-                OpCode::OpNegate => match self.stack.get(self.stack.len() - 1).expect("Failed to peek") {
-                    Value::Number(val) => {
-                        let neg_val = -*val;
-                        self.stack.pop();
-                        self.stack.push(Value::Number(neg_val));
-                    },
+            OpCode::OpNil => self.stack.push(Value::NIL),
+            OpCode::OpTrue => self.stack.push(Value::bool(true)),
+            OpCode::OpFalse => self.stack.push(Value::bool(false)),
+            OpCode::OpPop => { self.stack.pop().expect("Empty stack");},
+
+            OpCode::OpDefineGlobal | OpCode::OpDefineGlobalLong => {
+                let slot = self.read_index(opcode);
+                let val = self.stack.pop().expect("Empty stack");
+                self.globals[slot] = Some(val);
+            },
+
+            OpCode::OpGetLocal | OpCode::OpGetLocalLong => {
+                let slot = self.read_index(opcode) + self.frame().slots;
+                self.stack.push(self.stack[slot]);
+            },
+
+            OpCode::OpSetLocal | OpCode::OpSetLocalLong => {
+                let slot = self.read_index(opcode) + self.frame().slots;
+                self.stack[slot] = self.peek(0);
+            },
+
+            OpCode::OpGetGlobal | OpCode::OpGetGlobalLong => {
+                let slot = self.read_index(opcode);
+                match self.globals[slot] {
+                    Some(val) => self.stack.push(val),
+                    None => return Err(self.undefined_global(slot)),
+                }
+            },
+
+            OpCode::OpSetGlobal | OpCode::OpSetGlobalLong => {
+                let slot = self.read_index(opcode);
+                let val = self.peek(0);
+                match &mut self.globals[slot] {
+                    Some(global) => *global = val,
+                    None => return Err(self.undefined_global(slot)),
+                }
+            },
+
+            OpCode::OpGetUpvalue => {
+                let slot = self.read_byte() as usize;
+                let upvalue = self.heap.upvalue(self.current_closure().upvalues[slot]);
+                let val = match upvalue.closed {
+                    Some(val) => val,
+                    None => self.stack[upvalue.location],
+                };
+                self.stack.push(val);
+            },
+
+            OpCode::OpSetUpvalue => {
+                let slot = self.read_byte() as usize;
+                let upvalue = self.current_closure().upvalues[slot];
+                let val = self.peek(0);
+                let upvalue = self.heap.upvalue_mut(upvalue);
+                match upvalue.closed {
+                    Some(_) => upvalue.closed = Some(val),
+                    None => self.stack[upvalue.location] = val,
+                }
+            },
+
+            OpCode::OpGetProperty | OpCode::OpGetPropertyLong => {
+                let instance = match self.as_instance(self.peek(0)) {
+                    Some(instance) => instance,
+                    None => return Err("Only instances have properties.".to_string()),
+                };
+                let name = self.read_string(opcode)?;
+
+                let instance = self.heap.instance(instance);
+                if let Some(&val) = instance.fields.get(&name) {
+                    self.stack.pop();
+                    self.stack.push(val);
+                } else {
+                    let class = instance.class;
+                    self.bind_method(class, name)?;
+                }
+            },
+
+            OpCode::OpSetProperty | OpCode::OpSetPropertyLong => {
+                let instance = match self.as_instance(self.peek(1)) {
+                    Some(instance) => instance,
+                    None => return Err("Only instances have fields.".to_string()),
+                };
+                let name = self.read_string(opcode)?;
+
+                let val = self.peek(0);
+                let size = self.heap.size_of(instance);
+                self.heap.instance_mut(instance).fields.insert(name, val);
+                self.account_growth(instance, size);
+
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(val);
+            },
+
+            OpCode::OpGetSuper | OpCode::OpGetSuperLong => {
+                let name = self.read_string(opcode)?;
+                let superclass = match self.as_class(self.peek(0)) {
+                    Some(class) => class,
+                    None => return Err("Superclass must be a class.".to_string()),
+                };
+                self.stack.pop();
+                self.bind_method(superclass, name)?;
+            },
+
+            OpCode::OpEqual => {
+                let val1 = self.stack.pop().expect("Empty stack");
+                let val2 = self.stack.pop().expect("Empty stack");
+                self.stack.push(Value::bool(values_equal(val1, val2)));
+            },
+
+            OpCode::OpGreater => self.binary_op_bool(|a, b| a > b)?,
+            OpCode::OpLess => self.binary_op_bool(|a, b| a < b)?,
+
+            OpCode::OpAdd => {
+                let (a, b) = (self.peek(1), self.peek(0));
+                match (a.kind(), b.kind()) {
+                    (ValueKind::Number(_), ValueKind::Number(_)) => self.binary_op(|a, b| a + b)?,
+                    _ if self.is_string(a) && self.is_string(b) => self.concatenate(),
                     _ => {
-                        return self.runtime_error("Operand must be a number.");
+                        return Err(format!(
+                            "Operands must be two numbers or two strings, got {} and {}.",
+                            a.type_name(&self.heap),
+                            b.type_name(&self.heap)
+                        ));
                     }
-                },
+                }
+            },
+            OpCode::OpSubtract => self.binary_op(|a, b| a - b)?,
+            OpCode::OpMultiply => self.binary_op(|a, b| a * b)?,
+            OpCode::OpDivide => self.binary_op(|a, b| a / b)?,
+            OpCode::OpNot => {
+                let val = self.stack.pop().unwrap();
+                self.stack.push(Value::bool(self.is_falsey(val)))
+            },
 
-                OpCode::OpNil => self.stack.push(Value::Nil),
-                OpCode::OpTrue => self.stack.push(Value::Bool(true)),
-                OpCode::OpFalse => self.stack.push(Value::Bool(false)),
-                OpCode::OpPop => { self.stack.pop().expect("Empty stack");},
+            OpCode::OpPrint => {
+                let val = self.stack.pop().expect("Empty stack");
+                writeln!(self.output, "{}", val.display(&self.heap))
+                    .map_err(|err| format!("Could not write output: {}.", err))?;
+            },
 
-                OpCode::OpDefineGlobal => {
-                    let name = self.read_constant().clone();
-                    if let Value::ObjString(s) = name {
-                        let val = self.stack.pop().expect("Empty stack");
-                        self.globals.insert(s, val);
-                    } else {
-                        panic!("Unable to read global variable");
-                    }
-                },
+            OpCode::OpJumpIfFalse | OpCode::OpJumpIfFalseLong => {
+                let offset = self.read_jump(opcode);
+                if self.is_falsey(self.peek(0)) {
+                    self.frame_mut().ip += offset;
+                }
+            },
 
-                OpCode::OpGetLocal => {
-                    let slot: u8 = self.read_opcode().into();
-                    self.stack.push(self.stack[slot as usize].clone());
-                },
+            OpCode::OpJump | OpCode::OpJumpLong => {
+                let offset = self.read_jump(opcode);
+                self.frame_mut().ip += offset;
+            },
 
-                OpCode::OpSetLocal => {
-                    let slot: u8 = self.read_opcode().into();
-                    self.stack[slot as usize] = self.peek(0).clone();
-                },
+            OpCode::OpLoop | OpCode::OpLoopLong => {
+                let offset = self.read_jump(opcode);
+                self.frame_mut().ip -= offset;
+            },
 
-                OpCode::OpGetGlobal => {
-                    if let Value::ObjString(s) = self.read_constant().clone() {
-                        if let Some(v) = self.globals.get(&s) {
-                            self.stack.push(v.clone());
-                        } else {
-                            return self.runtime_error("Undefined variable .");
-                        }
-                    } else {
-                        panic!("Unable to read constant from table.");
-                    }
-                },
+            OpCode::OpCall => {
+                let arg_count = self.read_byte() as usize;
+                let callee = self.peek(arg_count);
+                self.call_value(callee, arg_count)?;
+            },
 
-                OpCode::OpSetGlobal => {
-                    if let Value::ObjString(s) = self.read_constant().clone() {
-                        let val = self.peek(0).clone();
-                        if let Entry::Occupied(mut o) = self.globals.entry(s.clone()) {
-                            *o.get_mut() = val;
-                        } else {
-                            return self.runtime_error("Undefined variable ");
-                        }
+            OpCode::OpClosure | OpCode::OpClosureLong => {
+                let function = match self.read_constant(opcode)?.as_obj() {
+                    Some(r) if matches!(self.heap.get(r), Obj::Function(_)) => r,
+                    _ => return Err("Closure constant is not a function.".to_string()),
+                };
+                let upvalue_count = self.heap.function(function).upvalue_count;
+                // Push the closure before capturing, so the upvalues allocated
+                // below cannot collect it.
+                let upvalues = Vec::with_capacity(upvalue_count);
+                let closure = self.alloc(Obj::Closure(Closure::new(function, upvalues)));
+                self.stack.push(Value::obj(closure));
+                for _ in 0..upvalue_count {
+                    let flags = self.read_byte();
+                    let is_local = flags & 1 == 1;
+                    let index = if flags & 2 != 0 {
+                        self.read_u24()
                     } else {
-                        panic!("Unable to read constant from table.");
-                    }
-                },
+                        self.read_byte() as usize
+                    };
+                    let upvalue = if is_local {
+                        self.capture_upvalue(self.frame().slots + index)
+                    } else {
+                        self.current_closure().upvalues[index]
+                    };
+                    self.heap.closure_mut(closure).upvalues.push(upvalue);
+                }
+            },
 
-                OpCode::OpEqual => {
-                    let val1 = self.stack.pop().expect("Empty stack");
-                    let val2 = self.stack.pop().expect("Empty stack");
-                    self.stack.push(Value::Bool(values_equal(val1, val2)));
-                },
+            OpCode::OpCloseUpvalue => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.stack.pop();
+            },
 
-                OpCode::OpGreater =>  self.binary_op_bool(|a, b| a > b),
-                OpCode::OpLess => self.binary_op_bool(|a, b| a < b),
+            OpCode::OpReturn => {
+                let result = self.stack.pop().expect("Empty stack");
+                let frame = self.frames.pop().expect("No call frame");
+                self.close_upvalues(frame.slots);
+                self.stack.truncate(frame.slots);
+                if self.frames.len() == self.base_frame {
+                    return Ok(Some(result));
+                }
+                self.stack.push(result);
+            },
 
-                OpCode::OpAdd => {
-                    match (self.peek(0), self.peek(1)) {
-                        (Value::Number(_), Value::Number(_)) => { self.binary_op(|a, b| a + b) },
-                        (Value::ObjString(_), Value::ObjString(_)) => { self.concatenate().expect("Operands must be two strings."); },
-                        _ => return self.runtime_error("Operands must be two numbers or two strings."),
-                    }
-                },
-                OpCode::OpSubtract => self.binary_op(|a, b| a - b),
-                OpCode::OpMultiply => self.binary_op(|a, b| a * b),
-                OpCode::OpDivide => self.binary_op(|a, b| a / b),
-                OpCode::OpNot => {
-                    let val = self.stack.pop().unwrap();
-                    self.stack.push(Value::Bool(self.is_falsey(&val)))
-                },
+            OpCode::OpClass | OpCode::OpClassLong => {
+                let name = self.read_string(opcode)?;
+                let name = self.heap.string(name).to_string();
+                let class = self.alloc(Obj::Class(Class::new(name)));
+                self.stack.push(Value::obj(class));
+            },
 
-                OpCode::OpPrint => {
-                    print_value(&self.stack.pop().expect("Empty stack"));
-                },
+            OpCode::OpInherit => {
+                let superclass = match self.as_class(self.peek(1)) {
+                    Some(class) => class,
+                    None => return Err("Superclass must be a class.".to_string()),
+                };
+                let subclass = match self.as_class(self.peek(0)) {
+                    Some(class) => class,
+                    None => return Err("Only classes can inherit.".to_string()),
+                };
+                // Copy-down inheritance: methods defined later in the
+                // subclass body simply overwrite these entries.
+                let methods = self.heap.class(superclass).methods.clone();
+                let size = self.heap.size_of(subclass);
+                self.heap.class_mut(subclass).methods.extend(methods);
+                self.account_growth(subclass, size);
+                self.stack.pop();
+            },
 
-                OpCode::OpJumpIfFalse => {
-                    let offset = self.read_short();
-                    if self.is_falsey(self.peek(0)) {
-                        self.ip += offset;
-                    }
-                },
+            OpCode::OpMethod | OpCode::OpMethodLong => {
+                let name = self.read_string(opcode)?;
+                self.define_method(name)?;
+            },
+        }
+        Ok(None)
+    }
 
-                OpCode::OpJump => {
-                    let offset = self.read_short();
-                    self.ip += offset;
-                },
+    fn undefined_global(&self, slot: usize) -> String {
+        let name = self.global_name(slot).unwrap_or("?");
+        format!("Undefined variable '{}'.", name)
+    }
 
-                OpCode::OpLoop => {
-                    let offset = self.read_short();
-                    self.ip -= offset;
-                },
+    /// Joins the two strings on top of the stack. The caller has already
+    /// checked the operand types.
+    fn concatenate(&mut self) {
+        let (a, b) = match (self.peek(1).as_obj(), self.peek(0).as_obj()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => unreachable!("Operands must be strings."),
+        };
+        let joined = format!("{}{}", self.heap.string(a), self.heap.string(b));
+        // Both operands stay on the stack until the result is allocated.
+        let result = self.intern(&joined);
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(Value::obj(result));
+    }
 
-                OpCode::OpReturn => { return InterpretResult::Ok; },
-            }
+    fn number_operands(&self) -> Result<(f64, f64), String> {
+        let (a, b) = (self.peek(1), self.peek(0));
+        match (a.as_number(), b.as_number()) {
+            (Some(a), Some(b)) => Ok((a, b)),
+            _ => Err(format!(
+                "Operands must be numbers, got {} and {}.",
+                a.type_name(&self.heap),
+                b.type_name(&self.heap)
+            )),
         }
     }
 
-    fn concatenate(&mut self)  -> Result<(), InterpretResult> {
-        let b = self.stack.pop().expect("Empty stack");
-        let a = self.stack.pop().expect("Empty stack");
-        match (b, a) {
-            (Value::ObjString(b), Value::ObjString(a)) => {
-                self.stack.push(Value::ObjString(a + &b));
-                Ok(())
-            }
-            _ => Err(self.runtime_error("Operands must be two strings."))
-        }
+    fn binary_op(&mut self, f: fn(f64, f64) -> f64) -> Result<(), String> {
+        let (a, b) = self.number_operands()?;
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(Value::number(f(a, b)));
+        Ok(())
     }
 
-    pub  fn binary_op(&mut self, f: fn(f64, f64) -> f64) {
-        let b = self.stack.pop().expect("Empty stack");
-        let a = self.stack.pop().expect("Empty stack");
+    fn binary_op_bool(&mut self, f: fn(f64, f64) -> bool) -> Result<(), String> {
+        let (a, b) = self.number_operands()?;
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(Value::bool(f(a, b)));
+        Ok(())
+    }
 
-        match (a, b) {
-            (Value::Number(a), Value::Number(b)) => {
-                let result = f(a, b);
-                self.stack.push(Value::Number(result));
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), String> {
+        let callee = match callee.as_obj() {
+            Some(r) => r,
+            None => {
+                let ty = callee.type_name(&self.heap);
+                return Err(format!("Can only call functions and classes, got {}.", ty));
             }
+        };
+
+        match self.heap.get(callee) {
+            Obj::Closure(_) => self.call(callee, arg_count),
+            Obj::Class(_) => {
+                // The class stays rooted in the callee slot until the
+                // instance replaces it.
+                let instance = self.alloc(Obj::Instance(Instance::new(callee)));
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = Value::obj(instance);
+
+                let initializer = self.heap.class(callee).methods.get(&self.init_string).copied();
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => {
+                        Err(format!("Expected 0 arguments but got {}.", arg_count))
+                    },
+                    None => Ok(()),
+                }
+            },
+            Obj::BoundMethod(bound) => {
+                let method = bound.method;
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = bound.receiver;
+                self.call(method, arg_count)
+            },
+            Obj::Native(native) => {
+                if arg_count != native.arity {
+                    return Err(format!("Expected {} arguments but got {}.", native.arity, arg_count));
+                }
+
+                let function = native.function;
+                let args_start = self.stack.len() - arg_count;
+                let args = self.stack[args_start..].to_vec();
+                let result = function(self, &args);
+                // Nothing allocates before the result is back on the stack.
+                self.temp_roots.clear();
+                self.stack.truncate(args_start - 1);
+                self.stack.push(result?);
+                Ok(())
+            },
             _ => {
-                self.runtime_error("Operands must be two numbers or two strings");
-            }
+                let ty = Value::obj(callee).type_name(&self.heap);
+                Err(format!("Can only call functions and classes, got {}.", ty))
+            },
+        }
+    }
+
+    fn define_method(&mut self, name: ObjRef) -> Result<(), String> {
+        let method = match self.as_closure(self.peek(0)) {
+            Some(closure) => closure,
+            None => return Err("Method body must be a closure.".to_string()),
+        };
+        let class = match self.as_class(self.peek(1)) {
+            Some(class) => class,
+            None => return Err("Methods can only be defined on classes.".to_string()),
+        };
+        let size = self.heap.size_of(class);
+        self.heap.class_mut(class).methods.insert(name, method);
+        self.account_growth(class, size);
+        self.stack.pop();
+        Ok(())
+    }
+
+    /// Replaces the instance on top of the stack with `name` bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), String> {
+        let method = match self.heap.class(class).methods.get(&name) {
+            Some(&method) => method,
+            None => return Err(format!("Undefined property '{}'.", self.heap.string(name))),
+        };
+
+        // The receiver is still on the stack while the bound method is
+        // allocated, so a collection here cannot free it.
+        let receiver = self.peek(0);
+        let bound = self.alloc(Obj::BoundMethod(BoundMethod::new(receiver, method)));
+        self.stack.pop();
+        self.stack.push(Value::obj(bound));
+        Ok(())
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), String> {
+        let arity = self.heap.function(self.heap.closure(closure).function).arity;
+        if arg_count != arity {
+            return Err(format!("Expected {} arguments but got {}.", arity, arg_count));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err("Stack overflow.".to_string());
         }
+
+        let slots = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame::new(closure, slots));
+        Ok(())
     }
 
-    pub  fn binary_op_bool(&mut self, f: fn(f64, f64) -> bool) {
-        let b = self.stack.pop().expect("Empty stack");
-        let a = self.stack.pop().expect("Empty stack");
+    fn capture_upvalue(&mut self, location: usize) -> ObjRef {
+        let heap = &self.heap;
+        let idx = self
+            .open_upvalues
+            .partition_point(|&upvalue| heap.upvalue(upvalue).location < location);
 
-        match (a, b) {
-            (Value::Number(a), Value::Number(b)) => {
-                let result = f(a, b);
-                self.stack.push(Value::Bool(result));
+        if let Some(&upvalue) = self.open_upvalues.get(idx) {
+            if self.heap.upvalue(upvalue).location == location {
+                return upvalue;
             }
-            _ => {
-                self.runtime_error("Operands must be boolean.");
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(Upvalue::new(location)));
+        self.open_upvalues.insert(idx, upvalue);
+        upvalue
+    }
+
+    /// Moves every open upvalue at or above `last` off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let location = self.heap.upvalue(upvalue).location;
+            if location < last {
+                break;
             }
+            self.heap.upvalue_mut(upvalue).closed = Some(self.stack[location]);
+            self.open_upvalues.pop();
         }
     }
 
-    fn peek(&self, distance: usize) -> &Value {
-        return self
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No call frame")
+    }
+
+    fn current_closure(&self) -> &Closure {
+        self.heap.closure(self.frame().closure)
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.heap.function(self.current_closure().function).chunk
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        *self
             .stack
             .get(self.stack.len() - 1 - distance)
-            .expect("Failed to peek");
+            .expect("Failed to peek")
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        frame.ip += 1;
+        let ip = frame.ip - 1;
+        self.chunk().read_byte(ip)
     }
 
     fn read_short(&mut self) -> usize {
-        self.ip += 2;
-        (((self.chunk.code[self.ip - 2] as u16) << 8) | self.chunk.code[self.ip - 1] as u16) as usize
+        let hi = self.read_byte() as u16;
+        let lo = self.read_byte() as u16;
+        ((hi << 8) | lo) as usize
+    }
+
+    fn read_u24(&mut self) -> usize {
+        let hi = self.read_byte() as usize;
+        let mid = self.read_byte() as usize;
+        let lo = self.read_byte() as usize;
+        (hi << 16) | (mid << 8) | lo
+    }
+
+    fn read_jump(&mut self, opcode: OpCode) -> usize {
+        if opcode.is_long() {
+            self.read_u24()
+        } else {
+            self.read_short()
+        }
+    }
+
+    /// Reads the index operand of `opcode`, which is wider for long forms.
+    fn read_index(&mut self, opcode: OpCode) -> usize {
+        if opcode.is_long() {
+            self.read_u24()
+        } else {
+            self.read_byte() as usize
+        }
+    }
+
+    fn read_opcode(&mut self) -> Result<OpCode, String> {
+        let byte = self.read_byte();
+        OpCode::try_from(byte).map_err(|byte| format!("Invalid opcode {}.", byte))
+    }
+
+    fn constant(&self, idx: usize) -> Result<Value, String> {
+        self.chunk()
+            .get_constant(idx)
+            .ok_or_else(|| format!("Constant {} out of range.", idx))
     }
 
-    fn read_opcode(&mut self) -> OpCode {
-        let val = self.chunk.read_byte(self.ip).into();
-        self.ip += 1;
-        val
+    fn read_constant(&mut self, opcode: OpCode) -> Result<Value, String> {
+        let idx = self.read_index(opcode);
+        self.constant(idx)
     }
 
-    fn read_constant(&mut self) -> &Value {
-        let idx = self.chunk.read_byte(self.ip) as usize;
-        self.ip += 1;
-        self.chunk.get_constant(idx)
+    fn read_string(&mut self, opcode: OpCode) -> Result<ObjRef, String> {
+        match self.read_constant(opcode)?.as_obj() {
+            Some(r) if matches!(self.heap.get(r), Obj::String(_)) => Ok(r),
+            _ => Err("Name constant is not a string.".to_string()),
+        }
     }
 
-    fn is_falsey(&self, val: &Value) -> bool {
-        match *val {
-            Value::Bool(b) => !b,
-            Value::Nil => true,
+    fn is_string(&self, val: Value) -> bool {
+        matches!(val.as_obj(), Some(r) if matches!(self.heap.get(r), Obj::String(_)))
+    }
+
+    fn as_instance(&self, val: Value) -> Option<ObjRef> {
+        val.as_obj()
+            .filter(|&r| matches!(self.heap.get(r), Obj::Instance(_)))
+    }
+
+    fn as_class(&self, val: Value) -> Option<ObjRef> {
+        val.as_obj()
+            .filter(|&r| matches!(self.heap.get(r), Obj::Class(_)))
+    }
+
+    fn as_closure(&self, val: Value) -> Option<ObjRef> {
+        val.as_obj()
+            .filter(|&r| matches!(self.heap.get(r), Obj::Closure(_)))
+    }
+
+    fn is_falsey(&self, val: Value) -> bool {
+        match val.kind() {
+            ValueKind::Bool(b) => !b,
+            ValueKind::Nil => true,
             _ => false
         }
     }
 
-    pub fn debug_trace_execution(&self) {
-        println!("          ");
+    /// Writes the stack and the instruction about to run to the tracer, if
+    /// its filters select it.
+    #[cfg(feature = "trace")]
+    fn trace_instruction(&mut self) {
+        use std::fmt::Write;
+
+        let ip = self.frame().ip;
+        let chunk = self.chunk();
+        let function = &self.heap.function(self.current_closure().function).name;
+        let wanted = match &self.tracer {
+            Some(tracer) => tracer.wants(function, chunk.lines[ip]),
+            None => false,
+        };
+        if !wanted {
+            return;
+        }
+
+        let mut text = String::from("          ");
         for slot in self.stack.iter() {
-            print!("[ ");
-            print_value(slot);
-            print!(" ]");
+            let _ = write!(text, "[ {} ]", slot.repr(&self.heap));
         }
-        println!(" ");
+        text.push('\n');
+        let _ = chunk.disassemble_instruction(ip, self, &mut text);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.write(&text);
+        }
+    }
 
-        self.chunk.disassemble_instruction(self.ip);
+    fn runtime_error(&mut self, msg: &str) -> LoxError {
+        let trace: Vec<TraceFrame> = self.frames[self.base_frame..]
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.function(self.heap.closure(frame.closure).function);
+                TraceFrame {
+                    function: function.name.clone(),
+                    line: function.chunk.lines[frame.ip - 1],
+                }
+            })
+            .collect();
+
+        let diagnostic = Diagnostic {
+            kind: ErrorKind::Runtime,
+            line: trace.first().map_or(0, |frame| frame.line),
+            column: None,
+            lexeme: None,
+            message: msg.to_string(),
+        };
+
+        // Unwind only the script that failed; a native that re-entered the
+        // VM gets the error back with its caller's frames intact.
+        self.close_upvalues(self.base_slot);
+        self.stack.truncate(self.base_slot);
+        self.frames.truncate(self.base_frame);
+        LoxError::runtime(diagnostic, trace)
     }
+}
 
-    fn runtime_error(&self, msg: &str) -> InterpretResult {
-        eprintln!("{}", msg);
+/// An in-memory sink that can still be read after a clone of it has been
+/// handed to the VM.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
 
-        let instruction = self.ip - 1;
-        let line = self.chunk.lines[instruction];
-        eprintln!("[line {}] in script", line);
-        InterpretResult::RuntimeError
+impl SharedBuffer {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
     }
-}
\ No newline at end of file
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
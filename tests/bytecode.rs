@@ -0,0 +1,177 @@
+mod common;
+
+use common::{crc32, forge, function_header};
+use rslox::serialize::{BytecodeError, MAGIC, MAX_FUNCTION_DEPTH};
+use rslox::{ErrorKind, OpCode, Value, VM};
+
+const PROGRAM: &str = "
+class Counter {
+  init(step) { this.step = step; this.count = 0; }
+  tick() { this.count = this.count + this.step; return this.count; }
+}
+fun adder(n) {
+  fun add(x) { return x + n; }
+  return add;
+}
+var counter = Counter(3);
+counter.tick();
+var ticks = counter.tick();
+var added = adder(10)(5);
+var greeting = \"hello\" + \", \" + \"world\";
+";
+
+fn number(vm: &VM, name: &str) -> f64 {
+    f64::try_from(vm.get_global(name).expect(name)).unwrap()
+}
+
+fn string(vm: &VM, name: &str) -> String {
+    vm.get_global(name).expect(name).display(vm.heap()).to_string()
+}
+
+fn load_error(bytes: &[u8]) -> String {
+    let err = VM::new().interpret_bytecode(bytes).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Bytecode);
+    err.diagnostics[0].message.clone()
+}
+
+#[test]
+fn round_trip_matches_interpreting_source() {
+    let bytes = VM::new().compile(PROGRAM).unwrap();
+    assert!(bytes.starts_with(MAGIC));
+
+    let mut vm = VM::new();
+    vm.interpret_bytecode(&bytes).unwrap();
+    assert_eq!(number(&vm, "ticks"), 6.0);
+    assert_eq!(number(&vm, "added"), 15.0);
+    assert_eq!(string(&vm, "greeting"), "hello, world");
+
+    let mut source_vm = VM::new();
+    source_vm.interpret(PROGRAM).unwrap();
+    assert_eq!(number(&source_vm, "ticks"), number(&vm, "ticks"));
+}
+
+#[test]
+fn round_trip_keeps_long_operands() {
+    let mut source = String::new();
+    for i in 0..300 {
+        source.push_str(&format!("var g{} = {}.5;\n", i, i));
+    }
+    source.push_str("var last = g299;");
+
+    let bytes = VM::new().compile(&source).unwrap();
+    let mut vm = VM::new();
+    vm.interpret_bytecode(&bytes).unwrap();
+    assert_eq!(number(&vm, "g0"), 0.5);
+    assert_eq!(number(&vm, "last"), 299.5);
+}
+
+#[test]
+fn runtime_errors_report_original_lines() {
+    let bytes = VM::new().compile("var a = 1;\n\nvar b = a + nil;").unwrap();
+    let err = VM::new().interpret_bytecode(&bytes).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Runtime);
+    assert_eq!(err.diagnostics[0].line, 3);
+}
+
+#[test]
+fn globals_are_remapped_into_the_loading_vm() {
+    let bytes = VM::new().compile("var answer = 42; var copy = answer;").unwrap();
+
+    let mut vm = VM::new();
+    vm.define_native("seven", 0, |_, _| Ok(Value::number(7.0)));
+    vm.set_global("copy", Value::NIL);
+    vm.interpret_bytecode(&bytes).unwrap();
+    assert_eq!(number(&vm, "answer"), 42.0);
+    assert_eq!(number(&vm, "copy"), 42.0);
+
+    vm.interpret("var sum = seven() + copy;").unwrap();
+    assert_eq!(number(&vm, "sum"), 49.0);
+}
+
+#[test]
+fn rejects_files_without_the_magic_header() {
+    assert_eq!(load_error(b"print 1;"), BytecodeError::BadMagic.to_string());
+}
+
+#[test]
+fn rejects_truncated_headers() {
+    let bytes = VM::new().compile("var a = 1;").unwrap();
+    assert_eq!(
+        load_error(&bytes[..MAGIC.len() + 1]),
+        BytecodeError::Truncated("header").to_string()
+    );
+}
+
+#[test]
+fn rejects_other_format_versions() {
+    let mut bytes = VM::new().compile("var a = 1;").unwrap();
+    bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&1u16.to_le_bytes());
+    let end = bytes.len() - 4;
+    let crc = crc32(&bytes[..end]);
+    bytes[end..].copy_from_slice(&crc.to_le_bytes());
+    assert_eq!(load_error(&bytes), BytecodeError::UnsupportedVersion(1).to_string());
+}
+
+#[test]
+fn rejects_corrupted_files() {
+    let mut bytes = VM::new().compile(PROGRAM).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0x40;
+    assert_eq!(load_error(&bytes), BytecodeError::BadChecksum.to_string());
+
+    let bytes = VM::new().compile(PROGRAM).unwrap();
+    assert_eq!(load_error(&bytes[..bytes.len() - 1]), BytecodeError::BadChecksum.to_string());
+}
+
+#[test]
+fn rejects_line_tables_longer_than_the_code() {
+    let code = [OpCode::OpNil.into(), OpCode::OpReturn.into()];
+    let mut script = function_header(&code, &[(1, 2)]);
+    script.extend_from_slice(&0u32.to_le_bytes());
+    VM::new().interpret_bytecode(&forge(&script)).unwrap();
+
+    for runs in [&[(1, u32::MAX)][..], &[(1, 3)], &[(1, 1), (2, 2)]] {
+        let mut script = function_header(&code, runs);
+        script.extend_from_slice(&0u32.to_le_bytes());
+        assert_eq!(load_error(&forge(&script)), BytecodeError::LineTableTooLong.to_string());
+    }
+}
+
+#[test]
+fn rejects_scripts_with_parameters_or_upvalues() {
+    // The header fields after the empty name: arity, then upvalue count.
+    let forged = |code: &[u8], field: usize| {
+        let mut script = function_header(code, &[(1, code.len() as u32)]);
+        script[4 + 4 * field..8 + 4 * field].copy_from_slice(&1u32.to_le_bytes());
+        script.extend_from_slice(&0u32.to_le_bytes());
+        forge(&script)
+    };
+
+    let get_upvalue = [OpCode::OpGetUpvalue.into(), 0, OpCode::OpReturn.into()];
+    assert_eq!(load_error(&forged(&get_upvalue, 1)), BytecodeError::InvalidScript.to_string());
+    let get_argument = [OpCode::OpGetLocal.into(), 1, OpCode::OpReturn.into()];
+    assert_eq!(load_error(&forged(&get_argument, 0)), BytecodeError::InvalidScript.to_string());
+}
+
+#[test]
+fn rejects_functions_nested_too_deeply() {
+    let nested = |depth: usize| {
+        let mut script = Vec::new();
+        for _ in 0..depth {
+            script.extend(function_header(&[], &[]));
+            script.extend_from_slice(&1u32.to_le_bytes());
+            script.push(2);
+        }
+        script.extend(function_header(&[], &[]));
+        script.extend_from_slice(&0u32.to_le_bytes());
+        forge(&script)
+    };
+    assert_eq!(load_error(&nested(200_000)), BytecodeError::TooDeeplyNested.to_string());
+    assert_eq!(load_error(&nested(MAX_FUNCTION_DEPTH)), BytecodeError::TooDeeplyNested.to_string());
+    // Within the limit, loading gets as far as verifying the empty code.
+    assert!(load_error(&nested(MAX_FUNCTION_DEPTH - 1)).contains("runs past the end of the code"));
+
+    let source = "fun f() { ".repeat(50) + &"} ".repeat(50);
+    let bytes = VM::new().compile(&source).unwrap();
+    VM::new().interpret_bytecode(&bytes).unwrap();
+}
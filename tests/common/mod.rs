@@ -0,0 +1,57 @@
+//! Builds `.loxc` files by hand, for feeding the loader input the compiler
+//! would never produce.
+
+#![allow(dead_code)]
+
+use rslox::serialize::{MAGIC, VERSION};
+
+const TAG_STRING: u8 = 1;
+
+/// Wraps a hand-written script `function` in a valid header and checksum.
+pub fn forge(function: &[u8]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(function);
+    let crc = crc32(&bytes);
+    bytes.extend_from_slice(&crc.to_le_bytes());
+    bytes
+}
+
+/// A script of `code`, all on line 1, whose constants are `strings`.
+pub fn script(code: &[u8], strings: &[&str]) -> Vec<u8> {
+    let mut function = function_header(code, &[(1, code.len() as u32)]);
+    function.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+    for s in strings {
+        function.push(TAG_STRING);
+        function.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        function.extend_from_slice(s.as_bytes());
+    }
+    forge(&function)
+}
+
+/// An unnamed function with no arguments or upvalues, up to its constants.
+pub fn function_header(code: &[u8], line_runs: &[(u32, u32)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for field in [0, 0, 0, code.len() as u32] {
+        bytes.extend_from_slice(&field.to_le_bytes());
+    }
+    bytes.extend_from_slice(code);
+    bytes.extend_from_slice(&(line_runs.len() as u32).to_le_bytes());
+    for &(line, len) in line_runs {
+        bytes.extend_from_slice(&line.to_le_bytes());
+        bytes.extend_from_slice(&len.to_le_bytes());
+    }
+    bytes
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
@@ -0,0 +1,57 @@
+use rslox::{Diagnostic, ErrorKind, VM};
+
+fn compile_errors(source: &str) -> Vec<Diagnostic> {
+    let err = VM::new().interpret(source).expect_err(source);
+    assert_eq!(err.kind, ErrorKind::Compile, "{}", source);
+    assert!(err.trace.is_empty());
+    err.diagnostics
+}
+
+fn diagnostic(line: usize, column: usize, lexeme: Option<&str>, message: &str) -> Diagnostic {
+    Diagnostic {
+        kind: ErrorKind::Compile,
+        line,
+        column: Some(column),
+        lexeme: lexeme.map(str::to_string),
+        message: message.to_string(),
+    }
+}
+
+#[test]
+fn reports_the_offending_token() {
+    let errors = compile_errors("1 +;");
+    assert_eq!(errors, [diagnostic(1, 4, Some(";"), "Expect expression.")]);
+    assert_eq!(errors[0].to_string(), "[line 1] Error at ';': Expect expression.");
+}
+
+#[test]
+fn columns_count_from_the_start_of_the_line() {
+    let errors = compile_errors("var a = 1;\n  var = 2;");
+    assert_eq!(errors, [diagnostic(2, 7, Some("="), "Expect variable name.")]);
+}
+
+#[test]
+fn end_of_input_has_an_empty_lexeme() {
+    let errors = compile_errors("print 1");
+    assert_eq!(errors, [diagnostic(1, 8, Some(""), "Expect ';' after value.")]);
+    assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect ';' after value.");
+}
+
+#[test]
+fn scanner_errors_have_no_lexeme() {
+    let errors = compile_errors("print 1;\nprint @;");
+    assert_eq!(errors, [diagnostic(2, 7, None, "Unexpected character.")]);
+    assert_eq!(errors[0].to_string(), "[line 2] Error: Unexpected character.");
+}
+
+#[test]
+fn reports_one_error_per_statement() {
+    let errors = compile_errors("1 +;\nvar = 2;\nprint 3;");
+    assert_eq!(
+        errors,
+        [
+            diagnostic(1, 4, Some(";"), "Expect expression."),
+            diagnostic(2, 5, Some("="), "Expect variable name."),
+        ]
+    );
+}
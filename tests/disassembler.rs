@@ -0,0 +1,110 @@
+use rslox::heap::Obj;
+use rslox::{OpCode, Operand, Parser, VM};
+
+#[test]
+fn lists_the_script_and_nested_functions() {
+    let mut vm = VM::new();
+    let listing = vm
+        .disassemble("var a = 1;\nfun f(x) { var y = x; return y; }\nprint f(a);")
+        .unwrap();
+
+    let expected = "\
+== <script> ==
+0000    1 OP_CONSTANT         0 '1'
+0002    | OP_DEFINE_GLOBAL    1 'a'
+0004    2 OP_CLOSURE          1 <fn f>
+0006    | OP_DEFINE_GLOBAL    2 'f'
+0008    3 OP_GET_GLOBAL       2 'f'
+0010    | OP_GET_GLOBAL       1 'a'
+0012    | OP_CALL             1
+0014    | OP_PRINT
+0015    | OP_NIL
+0016    | OP_RETURN
+
+== f ==
+0000    2 OP_GET_LOCAL        1
+0002    | OP_GET_LOCAL        2
+0004    | OP_RETURN
+0005    | OP_NIL
+0006    | OP_RETURN
+";
+    assert_eq!(listing, expected);
+}
+
+#[test]
+fn resolves_jump_targets() {
+    let mut vm = VM::new();
+    let script = Parser::new("while (false) print 1;", &mut vm).compile().unwrap();
+    let instructions = script.chunk.instructions(&vm);
+
+    let exit = instructions.iter().find(|i| i.op == OpCode::OpJumpIfFalse).unwrap();
+    let back = instructions.iter().find(|i| i.op == OpCode::OpLoop).unwrap();
+    assert!(matches!(back.operand, Operand::Jump { target: 0 }));
+    match exit.operand {
+        Operand::Jump { target } => {
+            assert_eq!(target, back.next);
+            assert!(instructions.iter().any(|i| i.offset == target));
+        }
+        ref operand => panic!("unexpected operand {:?}", operand),
+    }
+}
+
+#[test]
+fn describes_closure_captures() {
+    let mut vm = VM::new();
+    let source = "fun outer() { var a = 1; var b = 2; fun inner() { return a + b; } }";
+    let script = Parser::new(source, &mut vm).compile().unwrap();
+    let outer = script.chunk.constants[0].as_obj().unwrap();
+    let outer = match vm.heap().get(outer) {
+        Obj::Function(function) => function,
+        _ => panic!("expected a function"),
+    };
+
+    let closure = outer
+        .chunk
+        .instructions(&vm)
+        .into_iter()
+        .find(|i| i.op == OpCode::OpClosure)
+        .unwrap();
+    match closure.operand {
+        Operand::Closure { captures, .. } => {
+            let slots: Vec<_> = captures.iter().map(|c| (c.is_local, c.index)).collect();
+            assert_eq!(slots, [(true, 1), (true, 2)]);
+        }
+        operand => panic!("unexpected operand {:?}", operand),
+    }
+}
+
+#[test]
+fn writes_to_io_sinks() {
+    let mut vm = VM::new();
+    let script = Parser::new("print 1;", &mut vm).compile().unwrap();
+
+    let mut bytes = Vec::new();
+    script.chunk.write_disassembly("demo", &vm, &mut bytes).unwrap();
+    let mut text = String::new();
+    script.chunk.disassemble_chunk("demo", &vm, &mut text).unwrap();
+    assert_eq!(String::from_utf8(bytes).unwrap(), text);
+    assert!(text.starts_with("== demo ==\n0000    1 OP_CONSTANT"));
+}
+
+#[test]
+fn reports_compile_errors() {
+    let mut vm = VM::new();
+    assert!(vm.disassemble("print ;").is_err());
+}
+
+#[test]
+fn separates_long_names_from_operands() {
+    let source: String = (0..300).map(|i| format!("var g{} = {}.5;\n", i, i)).collect();
+    let source = source + "g299 = 1;";
+    let listing = VM::new().disassemble(&source).unwrap();
+
+    for line in [
+        "OP_CONSTANT_LONG  256 '256.5'",
+        "OP_DEFINE_GLOBAL_LONG  299 'g298'",
+        "OP_SET_GLOBAL_LONG  300 'g299'",
+    ] {
+        assert!(listing.contains(line), "missing {:?} in\n{}", line, listing);
+    }
+}
@@ -0,0 +1,143 @@
+use std::rc::Rc;
+use rslox::heap::Obj;
+use rslox::{Value, VM};
+
+fn global_string(vm: &VM, name: &str) -> String {
+    let val = vm.get_global(name).expect(name);
+    val.display(vm.heap()).to_string()
+}
+
+#[test]
+fn collects_cycles_between_instances_and_closures() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "class Node {}
+         fun make() {
+           var node = Node();
+           fun get() { return node; }
+           node.get = get;
+         }",
+    )
+    .unwrap();
+    vm.collect_garbage();
+    let baseline = vm.heap().object_count();
+
+    vm.interpret("for (var i = 0; i < 100; i = i + 1) make();").unwrap();
+    assert!(vm.heap().object_count() > baseline);
+
+    vm.collect_garbage();
+    assert_eq!(vm.heap().object_count(), baseline);
+}
+
+#[test]
+fn keeps_reachable_objects() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "class Pair { init(a, b) { this.a = a; this.b = b; } }
+         var pair = Pair(\"left\", \"right\");",
+    )
+    .unwrap();
+    vm.collect_garbage();
+
+    vm.interpret("var joined = pair.a + \"-\" + pair.b;").unwrap();
+    assert_eq!(global_string(&vm, "joined"), "left-right");
+}
+
+#[test]
+fn programs_run_under_stress() {
+    let mut vm = VM::new();
+    vm.set_gc_stress(true);
+    vm.interpret(
+        "class Greeter {
+           init(greeting) { this.greeting = greeting; }
+           greet(name) { return this.greeting + \", \" + name; }
+         }
+         class Loud < Greeter {
+           greet(name) { return super.greet(name) + \"!\"; }
+         }
+         fun counter() {
+           var count = 0;
+           fun next() { count = count + 1; return count; }
+           return next;
+         }
+         var next = counter();
+         var message = \"\";
+         for (var i = 0; i < 3; i = i + 1) {
+           message = Loud(\"hello\").greet(\"world \" + \"#\");
+           next();
+         }
+         var count = next();",
+    )
+    .unwrap();
+
+    assert_eq!(global_string(&vm, "message"), "hello, world #!");
+    assert_eq!(f64::try_from(vm.get_global("count").unwrap()), Ok(4.0));
+}
+
+#[test]
+fn threshold_bounds_heap_growth() {
+    let mut vm = VM::new();
+    vm.set_gc_threshold(16 * 1024);
+    vm.interpret(
+        "var s = \"\";
+         for (var i = 0; i < 2000; i = i + 1) { s = s + \"x\"; }",
+    )
+    .unwrap();
+
+    assert!(vm.heap().object_count() < 500, "{}", vm.heap().object_count());
+    assert!(vm.get_global("s").and_then(Value::as_obj).is_some());
+}
+
+#[test]
+fn equal_strings_share_one_object() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "var literal = \"ab\";
+         var built = \"a\" + \"b\";
+         var same = literal == built;",
+    )
+    .unwrap();
+
+    assert_eq!(bool::try_from(vm.get_global("same").unwrap()), Ok(true));
+    let literal = vm.get_global("literal").and_then(Value::as_obj);
+    let built = vm.get_global("built").and_then(Value::as_obj);
+    assert!(literal.is_some());
+    assert_eq!(literal, built);
+
+    // The intern table's key and the object hold the same allocation.
+    match vm.heap().get(literal.unwrap()) {
+        Obj::String(s) => assert_eq!(Rc::strong_count(s), 2),
+        other => panic!("Expected a string, found {:?}", other),
+    }
+}
+
+#[test]
+fn unreachable_interned_strings_are_collected() {
+    let mut vm = VM::new();
+    vm.interpret("var kept = \"kept\"; { var dropped = \"drop\" + \"ped\"; }").unwrap();
+    vm.collect_garbage();
+
+    assert!(vm.heap().find_string("kept").is_some());
+    assert!(vm.heap().find_string("dropped").is_none());
+}
+
+#[test]
+fn growing_instances_triggers_collections() {
+    let mut vm = VM::new();
+    let fields: String = (0..1000).map(|i| format!("  o.f{} = {};\n", i, i)).collect();
+    vm.interpret(&format!(
+        "class Foo {{}}
+         var o = Foo();
+         fun grow() {{\n{}}}
+         var s = \"gar\" + \"bage\";
+         s = nil;",
+        fields
+    ))
+    .unwrap();
+    assert!(vm.heap().find_string("garbage").is_some());
+
+    // Calling grow() allocates next to nothing; only the field table grows.
+    vm.set_gc_threshold(vm.heap().bytes_allocated() + 4096);
+    vm.interpret("grow();").unwrap();
+    assert!(vm.heap().find_string("garbage").is_none());
+}
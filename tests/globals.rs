@@ -0,0 +1,68 @@
+use rslox::VM;
+
+fn number(vm: &VM, name: &str) -> f64 {
+    f64::try_from(vm.get_global(name).expect(name)).unwrap()
+}
+
+#[test]
+fn globals_persist_across_interpret_calls() {
+    let mut vm = VM::new();
+    vm.interpret("fun later() { return next() + 1; }").unwrap();
+    vm.interpret("fun next() { return 41; }").unwrap();
+    vm.interpret("var answer = later();").unwrap();
+    assert_eq!(number(&vm, "answer"), 42.0);
+}
+
+#[test]
+fn use_before_definition_is_undefined() {
+    let mut vm = VM::new();
+    let err = vm.interpret("print early;\nvar early = 1;").unwrap_err();
+    assert_eq!(err.diagnostics[0].message, "Undefined variable 'early'.");
+    assert!(vm.get_global("early").is_none());
+
+    let err = vm.interpret("early = 2;").unwrap_err();
+    assert_eq!(err.diagnostics[0].message, "Undefined variable 'early'.");
+
+    vm.interpret("var early = 3;").unwrap();
+    assert_eq!(number(&vm, "early"), 3.0);
+}
+
+#[test]
+fn host_and_script_share_global_slots() {
+    let mut vm = VM::new();
+    vm.set_global("x", 1.0);
+    vm.interpret("x = x + 1; var y = x * 10;").unwrap();
+    vm.set_global("x", 5.0);
+    vm.interpret("y = y + x;").unwrap();
+    assert_eq!(number(&vm, "y"), 25.0);
+}
+
+#[test]
+fn lists_defined_globals_in_declaration_order() {
+    let mut vm = VM::new();
+    vm.interpret("var b = 2; fun f() { return undeclared; } var a = \"one\";").unwrap();
+
+    let globals: Vec<(String, String)> = vm
+        .globals()
+        .map(|(name, value)| (name.to_string(), value.repr(vm.heap()).to_string()))
+        .collect();
+    let expected = [("clock", "<native fn>"), ("b", "2"), ("f", "<fn f>"), ("a", "\"one\"")];
+    assert_eq!(globals.len(), expected.len(), "{:?}", globals);
+    for ((name, value), (want_name, want_value)) in globals.iter().zip(expected) {
+        assert_eq!((name.as_str(), value.as_str()), (want_name, want_value));
+    }
+}
+
+#[test]
+fn misplaced_super_declares_no_global() {
+    let mut vm = VM::new();
+    for source in [
+        "super.foo;",
+        "class A { m() { return super.m(); } }",
+        "fun f() { super.g(); }",
+    ] {
+        assert!(vm.interpret(source).is_err(), "{}", source);
+    }
+    let names: Vec<&str> = (0..).map_while(|slot| vm.global_name(slot)).collect();
+    assert!(!names.contains(&"super"), "{:?}", names);
+}
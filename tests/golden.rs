@@ -0,0 +1,212 @@
+//! Runs every `.lox` file under `tests/lox` and checks it against the
+//! annotations in its comments, in the format of the Crafting Interpreters
+//! test suite:
+//!
+//! - `// expect: text` - the program prints `text` as its next line.
+//! - `// expect runtime error: message` - execution stops with `message`,
+//!   raised on the annotated line.
+//! - `// Error at 'x': message` or `// [line N] Error ...` - compilation
+//!   reports this error, on the annotated line or on line `N`. The
+//!   `[c line N]` form is accepted too; `[java line N]` is ignored.
+//!
+//! The files are the upstream suite, unchanged. Where this implementation
+//! deliberately says more than clox - runtime errors that name the offending
+//! types, such as `Operands must be numbers, got number and string.` - the
+//! upstream message is matched as a prefix and the file is reported as a
+//! divergence rather than a failure.
+//!
+//! Set `LOX_TEST` to a path fragment to run only the matching files.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use rslox::{ErrorKind, VM};
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<(String, usize)>,
+}
+
+fn parse(source: &str) -> Expectations {
+    let mut expect = Expectations::default();
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let comment = match line.find("// ") {
+            Some(start) => &line[start + 3..],
+            None => continue,
+        };
+
+        if let Some(output) = comment.strip_prefix("expect: ") {
+            expect.output.push(output.to_string());
+        } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+            expect.runtime_error = Some((message.to_string(), line_number));
+        } else if let Some(error) = compile_error(comment, line_number) {
+            expect.compile_errors.extend(error);
+        }
+    }
+    expect
+}
+
+/// Parses a compile error annotation. Returns `Some(None)` for errors
+/// meant for another implementation.
+fn compile_error(comment: &str, line_number: usize) -> Option<Option<String>> {
+    if comment.starts_with("Error") {
+        return Some(Some(format!("[line {}] {}", line_number, comment)));
+    }
+
+    let rest = comment.strip_prefix('[')?;
+    let (tag, error) = rest.split_once("] ")?;
+    if !error.starts_with("Error") {
+        return None;
+    }
+    let (language, line) = match tag.split_once("line ") {
+        Some((language, line)) => (language.trim(), line.parse::<usize>().ok()?),
+        None => return None,
+    };
+    match language {
+        "" | "c" => Some(Some(format!("[line {}] {}", line, error))),
+        _ => Some(None),
+    }
+}
+
+/// Whether `got` is `want` with extra detail after a comma, as in
+/// `Operand must be a number, got string.` for `Operand must be a number.`.
+fn extends(got: &str, want: &str) -> bool {
+    let stem = want.strip_suffix('.').unwrap_or(want);
+    got.strip_prefix(stem).is_some_and(|rest| rest.starts_with(", "))
+}
+
+/// What running one file found.
+#[derive(Default)]
+struct Outcome {
+    failures: Vec<String>,
+    /// Known, accepted differences from the upstream expectations.
+    divergences: Vec<String>,
+}
+
+/// Runs one file, describing every mismatch.
+fn check(path: &Path) -> Outcome {
+    let source = fs::read_to_string(path).unwrap();
+    let expect = parse(&source);
+    let (result, out, err) = VM::new().interpret_capturing(&source);
+    let err_lines: Vec<&str> = err.lines().collect();
+    let mut outcome = Outcome::default();
+    let failures = &mut outcome.failures;
+
+    let out_lines: Vec<&str> = out.lines().collect();
+    for i in 0..expect.output.len().max(out_lines.len()) {
+        match (expect.output.get(i), out_lines.get(i)) {
+            (Some(want), Some(got)) if want == got => {}
+            (Some(want), Some(got)) => {
+                failures.push(format!("expected output '{}', got '{}'", want, got))
+            }
+            (Some(want), None) => failures.push(format!("missing output '{}'", want)),
+            (None, Some(got)) => failures.push(format!("unexpected output '{}'", got)),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    let kind = result.as_ref().err().map(|err| err.kind);
+    if !expect.compile_errors.is_empty() {
+        if kind != Some(ErrorKind::Compile) {
+            failures.push(format!("expected compile errors, got {:?}", kind));
+        }
+        for want in &expect.compile_errors {
+            if !err_lines.contains(&want.as_str()) {
+                failures.push(format!("missing error '{}'", want));
+            }
+        }
+        for got in &err_lines {
+            if !expect.compile_errors.iter().any(|want| want == got) {
+                failures.push(format!("unexpected error '{}'", got));
+            }
+        }
+    } else if let Some((message, line)) = &expect.runtime_error {
+        if kind != Some(ErrorKind::Runtime) {
+            failures.push(format!("expected runtime error, got {:?}", kind));
+        }
+        let (got_message, got_location) = (err_lines.first(), err_lines.get(1));
+        match got_message {
+            Some(got) if got == message => {}
+            Some(got) if extends(got, message) => outcome
+                .divergences
+                .push(format!("runtime error '{}' extends '{}'", got, message)),
+            _ => failures.push(format!("expected runtime error '{}', got {:?}", message, got_message)),
+        }
+        let location = format!("[line {}]", line);
+        if !got_location.is_some_and(|got| got.starts_with(&location)) {
+            failures.push(format!("expected error on line {}, got {:?}", line, got_location));
+        }
+    } else if let Err(err) = result {
+        failures.push(format!("unexpected error:\n{}", err));
+    }
+    outcome
+}
+
+fn lox_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> =
+        fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            lox_files(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            files.push(path);
+        }
+    }
+}
+
+#[test]
+fn golden_files() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let filter = env::var("LOX_TEST").unwrap_or_default();
+    let mut files = Vec::new();
+    lox_files(&root, &mut files);
+
+    // Passed, diverged and total files per chapter directory.
+    let mut chapters: BTreeMap<String, (usize, usize, usize)> = BTreeMap::new();
+    let mut report = String::new();
+    let mut divergences = String::new();
+    for path in files {
+        let name = path.strip_prefix(&root).unwrap().to_string_lossy().into_owned();
+        if !name.contains(&filter) {
+            continue;
+        }
+        let chapter = match name.split_once('/') {
+            Some((chapter, _)) => chapter.to_string(),
+            None => "(top level)".to_string(),
+        };
+        let outcome = check(&path);
+        let counts = chapters.entry(chapter).or_default();
+        counts.2 += 1;
+        if !outcome.failures.is_empty() {
+            let _ = writeln!(report, "FAIL {}", name);
+            for failure in outcome.failures {
+                let _ = writeln!(report, "    {}", failure);
+            }
+        } else if !outcome.divergences.is_empty() {
+            counts.1 += 1;
+            let _ = writeln!(divergences, "DIVERGES {}", name);
+            for divergence in outcome.divergences {
+                let _ = writeln!(divergences, "    {}", divergence);
+            }
+        } else {
+            counts.0 += 1;
+        }
+    }
+
+    let mut summary = String::new();
+    for (chapter, (passed, diverged, total)) in &chapters {
+        let _ = write!(summary, "{:<20} {:>3}/{}", chapter, passed, total);
+        if *diverged > 0 {
+            let _ = write!(summary, " ({} diverged)", diverged);
+        }
+        summary.push('\n');
+    }
+    println!("{}\n{}", summary, divergences);
+    assert!(report.is_empty(), "\n{}\n{}", report, summary);
+}
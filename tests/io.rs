@@ -0,0 +1,83 @@
+use std::cell::RefCell;
+use std::io::{self, Cursor, Write};
+use std::rc::Rc;
+use rslox::{ErrorKind, Value, VM};
+
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[test]
+fn captures_program_output() {
+    let mut vm = VM::new();
+    let (result, out, err) = vm.interpret_capturing("print 1 + 2; print \"two\"; print nil;");
+    assert!(result.is_ok());
+    assert_eq!(out, "3\ntwo\nnil\n");
+    assert_eq!(err, "");
+}
+
+#[test]
+fn captures_runtime_errors_after_earlier_output() {
+    let mut vm = VM::new();
+    let (result, out, err) = vm.interpret_capturing("print \"before\";\nprint -nil;");
+    assert_eq!(result.unwrap_err().kind, ErrorKind::Runtime);
+    assert_eq!(out, "before\n");
+    assert_eq!(err, "Operand must be a number, got nil.\n[line 2] in script\n");
+}
+
+#[test]
+fn captures_compile_errors() {
+    let mut vm = VM::new();
+    let (result, out, err) = vm.interpret_capturing("print ;");
+    assert_eq!(result.unwrap_err().kind, ErrorKind::Compile);
+    assert_eq!(out, "");
+    assert!(err.contains("Expect expression."), "{}", err);
+}
+
+#[test]
+fn configured_sinks_are_restored_after_capturing() {
+    let out = Shared::default();
+    let diagnostics = Shared::default();
+    let mut vm = VM::new();
+    vm.set_output(Box::new(out.clone()));
+    vm.set_diagnostics(Box::new(diagnostics.clone()));
+
+    vm.interpret_capturing("print \"captured\"; nil();").0.unwrap_err();
+    vm.interpret("print \"direct\";").unwrap();
+    vm.interpret("print undefined;").unwrap_err();
+
+    assert_eq!(out.text(), "direct\n");
+    assert!(diagnostics.text().starts_with("Undefined variable 'undefined'."));
+}
+
+#[test]
+fn natives_read_from_the_configured_input() {
+    fn read_line(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+        let mut line = String::new();
+        vm.input().read_line(&mut line).map_err(|err| err.to_string())?;
+        Ok(Value::number(line.trim().len() as f64))
+    }
+
+    let mut vm = VM::new();
+    vm.set_input(Box::new(Cursor::new("first\nsecond line\n")));
+    vm.define_native("lineLength", 0, read_line);
+    let (result, out, _) = vm.interpret_capturing("print lineLength(); print lineLength();");
+    result.unwrap();
+    assert_eq!(out, "5\n11\n");
+}
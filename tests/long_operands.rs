@@ -0,0 +1,180 @@
+use rslox::VM;
+
+fn number(vm: &VM, name: &str) -> f64 {
+    f64::try_from(vm.get_global(name).expect(name)).unwrap()
+}
+
+/// `count` statements adding a distinct constant each to `sum`.
+fn constant_sums(count: usize) -> String {
+    (0..count).map(|i| format!("sum = sum + {};\n", i)).collect()
+}
+
+#[test]
+fn constants_past_one_byte_use_long_form() {
+    for count in [255, 256, 257, 1000] {
+        let source = format!("var sum = 0;\n{}", constant_sums(count));
+        let mut vm = VM::new();
+        vm.interpret(&source).unwrap();
+        assert_eq!(number(&vm, "sum"), (count * (count - 1) / 2) as f64, "{}", count);
+    }
+}
+
+#[test]
+fn constants_are_counted_per_function() {
+    let source = format!(
+        "fun total() {{ var sum = 0;\n{}return sum; }}\nvar result = total();",
+        constant_sums(300)
+    );
+    let mut vm = VM::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(number(&vm, "result"), 44850.0);
+}
+
+#[test]
+fn globals_past_one_byte_use_long_form() {
+    let mut source: String = (0..300).map(|i| format!("var g{} = {};\n", i, i)).collect();
+    source.push_str("g299 = g299 + g255 + g256;\nvar last = g299;");
+
+    let mut vm = VM::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(number(&vm, "last"), 810.0);
+    assert_eq!(number(&vm, "g0"), 0.0);
+}
+
+/// Statements compiling to exactly `bytes` bytes of bytecode, given a
+/// global `x`: `nil;` is two bytes and `x;` three.
+fn padding(bytes: usize) -> String {
+    let mut pad = String::new();
+    let nils = if bytes % 2 == 1 {
+        pad.push_str("x;\n");
+        (bytes - 3) / 2
+    } else {
+        bytes / 2
+    };
+    pad.push_str(&"nil;\n".repeat(nils));
+    pad
+}
+
+/// Body sizes straddling the old 256-byte limit and the 16-bit jump limit.
+fn jump_sizes() -> impl Iterator<Item = usize> {
+    (250..262).chain(65528..65542)
+}
+
+#[test]
+fn locals_past_one_byte_use_long_form() {
+    for count in [254, 255, 256, 257, 300] {
+        let decls: String = (0..count).map(|i| format!("var l{} = {};\n", i, i)).collect();
+        let last = count - 1;
+        let source = format!(
+            "fun f() {{\n{}l{} = l{} + 1;\nreturn l0 + l{};\n}}\nvar result = f();",
+            decls, last, last, last
+        );
+
+        let mut vm = VM::new();
+        vm.interpret(&source).unwrap();
+        assert_eq!(number(&vm, "result"), count as f64, "{}", count);
+    }
+}
+
+#[test]
+fn closures_capture_locals_past_one_byte() {
+    let decls: String = (0..300).map(|i| format!("var l{};\n", i)).collect();
+    let source = format!(
+        "fun outer() {{\n{}l1 = 1;\nl299 = 299;\nfun inner() {{ l299 = l299 + 1; return l299 + l1; }}\nreturn inner;\n}}\n\
+         var inner = outer();\ninner();\nvar result = inner();",
+        decls
+    );
+
+    let mut vm = VM::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(number(&vm, "result"), 302.0);
+}
+
+#[test]
+fn forward_jumps_past_sixteen_bits() {
+    for size in jump_sizes() {
+        let source = format!(
+            "var x = 0;\nvar taken = 0;\n\
+             if (flag) {{ taken = 1;\n{pad}}} else {{ taken = 2;\n{pad}}}\n\
+             var after = taken * 10;",
+            pad = padding(size)
+        );
+
+        for (flag, taken) in [(true, 1.0), (false, 2.0)] {
+            let mut vm = VM::new();
+            vm.set_global("flag", flag);
+            vm.interpret(&source).unwrap();
+            assert_eq!(number(&vm, "taken"), taken, "{} {}", size, flag);
+            assert_eq!(number(&vm, "after"), taken * 10.0, "{} {}", size, flag);
+        }
+    }
+}
+
+#[test]
+fn loops_past_sixteen_bits() {
+    for size in jump_sizes() {
+        let source = format!(
+            "var x = 0;\nvar i = 0;\nwhile (i < 3) {{ i = i + 1;\n{}}}\nvar after = i;",
+            padding(size)
+        );
+
+        let mut vm = VM::new();
+        vm.interpret(&source).unwrap();
+        assert_eq!(number(&vm, "after"), 3.0, "{}", size);
+    }
+}
+
+/// Functions, classes and properties, declared after `constant_sums(300)`
+/// has filled the one-byte constant range.
+const AFTER_CONSTANTS: &str = "
+fun f() { return sum; }
+class Base { value() { return this.field; } }
+class Derived < Base {
+  init() { this.field = f(); }
+  value() { return super.value() + 1; }
+}
+var result = Derived().value();
+";
+
+#[test]
+fn functions_classes_and_properties_after_many_constants() {
+    let source = format!("var sum = 0;\n{}{}", constant_sums(300), AFTER_CONSTANTS);
+    let mut vm = VM::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(number(&vm, "result"), 44851.0);
+
+    let listing = VM::new().disassemble(&source).unwrap();
+    for op in ["OP_CLOSURE_LONG", "OP_CLASS_LONG", "OP_METHOD_LONG"] {
+        assert!(listing.contains(op), "{}", op);
+    }
+
+    let bytes = VM::new().compile(&source).unwrap();
+    let mut vm = VM::new();
+    vm.interpret_bytecode(&bytes).unwrap();
+    assert_eq!(number(&vm, "result"), 44851.0);
+}
+
+#[test]
+fn methods_use_long_forms_after_many_constants() {
+    let source = format!(
+        "class A {{
+           total() {{ var sum = 0;\n{}
+             var step = 1;
+             fun add() {{ sum = sum + step; return sum; }}
+             this.last = add();
+             return this.last; }}
+         }}
+         class B < A {{ total() {{ var sum = 0;\n{} return super.total() + sum; }} }}
+         var result = B().total();",
+        constant_sums(300),
+        constant_sums(300)
+    );
+    let mut vm = VM::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(number(&vm, "result"), 89701.0);
+
+    let listing = VM::new().disassemble(&source).unwrap();
+    for op in ["OP_CLOSURE_LONG", "OP_SET_PROPERTY_LONG", "OP_GET_PROPERTY_LONG", "OP_GET_SUPER_LONG"] {
+        assert!(listing.contains(op), "{}", op);
+    }
+}
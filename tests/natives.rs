@@ -0,0 +1,60 @@
+use rslox::{Value, VM};
+
+/// Runs its argument as a script and returns whether it succeeded.
+fn eval(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let source = vm.as_str(args[0]).ok_or("Expected a string.")?.to_string();
+    let kept = vm.string("kept");
+    let ok = vm.interpret(&source).is_ok();
+    vm.set_global("kept", kept);
+    Ok(Value::bool(ok))
+}
+
+fn reentrant_vm() -> VM {
+    let mut vm = VM::new();
+    vm.set_gc_stress(true);
+    vm.define_native("eval", 1, eval);
+    vm
+}
+
+#[test]
+fn natives_can_run_scripts_in_the_middle_of_a_call() {
+    let mut vm = reentrant_vm();
+    let (result, out, err) = vm.interpret_capturing(
+        r#"fun f(n) {
+           print "before";
+           print eval("var inner = 2; print inner + 1;");
+           print "after";
+           return n + inner;
+         }
+         print f(1);
+         print kept;"#,
+    );
+    assert!(result.is_ok(), "{}", err);
+    assert_eq!(out, "before\n3\ntrue\nafter\n3\nkept\n");
+}
+
+#[test]
+fn errors_in_a_nested_script_leave_the_caller_running() {
+    let mut vm = reentrant_vm();
+    let negate_nil = vm.string("-nil;");
+    vm.set_global("negateNil", negate_nil);
+    let (result, out, err) = vm.interpret_capturing(
+        r#"var captured = "outer";
+         fun f() {
+           var local = "local";
+           fun g() { return local; }
+           print eval("fun h() { nil(); } h();");
+           print eval("print eval(negateNil); print 1;");
+           return g;
+         }
+         print f()();
+         print captured;"#,
+    );
+    assert!(result.is_ok(), "{}", err);
+    assert_eq!(out, "false\nfalse\n1\ntrue\nlocal\nouter\n");
+    assert_eq!(
+        err,
+        "Can only call functions and classes, got nil.\n[line 1] in h()\n[line 1] in script\n\
+         Operand must be a number, got nil.\n[line 1] in script\n"
+    );
+}
@@ -0,0 +1,70 @@
+use rslox::scanner::is_incomplete;
+use rslox::{ErrorKind, VM};
+
+fn echo(vm: &mut VM, source: &str) -> Option<String> {
+    let value = vm.interpret_repl(source).unwrap();
+    value.map(|value| value.repr(vm.heap()).to_string())
+}
+
+#[test]
+fn echoes_a_trailing_expression_statement() {
+    let mut vm = VM::new();
+    assert_eq!(echo(&mut vm, "1 + 2;"), Some("3".to_string()));
+    assert_eq!(echo(&mut vm, "\"text\";"), Some("\"text\"".to_string()));
+    assert_eq!(echo(&mut vm, "nil;"), Some("nil".to_string()));
+    assert_eq!(echo(&mut vm, "var a = 1; a = a + 1;"), Some("2".to_string()));
+}
+
+#[test]
+fn other_statements_echo_nothing() {
+    let mut vm = VM::new();
+    for source in [
+        "var b = 2;",
+        "print 1;",
+        "fun f() { return 1; }",
+        "{ 1; }",
+        "if (true) 1;",
+        "if (true) 1; else 2;",
+        "if (false) 1; else 2;",
+        "while (false) 1;",
+        "1; var c = 3;",
+    ] {
+        let (result, _, err) = vm.interpret_capturing(source);
+        result.unwrap();
+        assert_eq!(err, "");
+        assert_eq!(echo(&mut vm, source), None, "{}", source);
+    }
+}
+
+#[test]
+fn only_the_last_expression_is_echoed() {
+    let mut vm = VM::new();
+    let (result, out, _) = vm.interpret_capturing("print \"first\"; 1; 2;");
+    result.unwrap();
+    assert_eq!(out, "first\n");
+    assert_eq!(echo(&mut vm, "1; 2;"), Some("2".to_string()));
+}
+
+#[test]
+fn errors_are_still_reported() {
+    let mut vm = VM::new();
+    assert_eq!(vm.interpret_repl("1 +;").unwrap_err().kind, ErrorKind::Compile);
+    assert_eq!(vm.interpret_repl("-nil;").unwrap_err().kind, ErrorKind::Runtime);
+    assert_eq!(echo(&mut vm, "4;"), Some("4".to_string()));
+}
+
+#[test]
+fn detects_incomplete_input() {
+    assert!(is_incomplete("{"));
+    assert!(is_incomplete("fun f() {\n  print 1;\n"));
+    assert!(is_incomplete("print (1 +\n"));
+    assert!(is_incomplete("print \"line one\n"));
+    assert!(is_incomplete("class A {\n  m() {\n  }\n"));
+
+    assert!(!is_incomplete(""));
+    assert!(!is_incomplete("print 1;"));
+    assert!(!is_incomplete("{ print 1; }"));
+    assert!(!is_incomplete("print \"{\";"));
+    assert!(!is_incomplete("// {"));
+    assert!(!is_incomplete("}"));
+}
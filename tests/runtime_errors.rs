@@ -0,0 +1,110 @@
+use rslox::{ErrorKind, VM};
+
+/// Declarations giving every kind of value that is not a number.
+const PRELUDE: &str = "fun f() {} class C {} var i = C();";
+
+/// Non-number operands, paired with the type name reported for them.
+const BAD_OPERANDS: [(&str, &str); 8] = [
+    ("nil", "nil"),
+    ("true", "boolean"),
+    ("false", "boolean"),
+    ("\"s\"", "string"),
+    ("f", "function"),
+    ("C", "class"),
+    ("i", "instance"),
+    ("clock", "function"),
+];
+
+fn runtime_error(source: &str) -> String {
+    let mut vm = VM::new();
+    let err = vm
+        .interpret(&format!("{}\n{}", PRELUDE, source))
+        .expect_err(source);
+    assert_eq!(err.kind, ErrorKind::Runtime, "{}", source);
+    assert_eq!(err.diagnostics.len(), 1, "{}", source);
+    err.diagnostics[0].message.clone()
+}
+
+#[test]
+fn numeric_operators_reject_non_numbers() {
+    for op in ["-", "*", "/", "<", ">", "<=", ">="] {
+        for (bad, ty) in BAD_OPERANDS {
+            for (source, types) in [
+                (format!("{} {} 1;", bad, op), (ty, "number")),
+                (format!("1 {} {};", op, bad), ("number", ty)),
+                (format!("{} {} {};", bad, op, bad), (ty, ty)),
+            ] {
+                let expected = format!("Operands must be numbers, got {} and {}.", types.0, types.1);
+                assert_eq!(runtime_error(&source), expected, "{}", source);
+            }
+        }
+    }
+}
+
+#[test]
+fn add_rejects_mismatched_operands() {
+    for (bad, ty) in BAD_OPERANDS {
+        for (other, other_ty) in [("1", "number"), ("\"s\"", "string")] {
+            if bad == other {
+                continue;
+            }
+            for (source, types) in [
+                (format!("{} + {};", bad, other), (ty, other_ty)),
+                (format!("{} + {};", other, bad), (other_ty, ty)),
+            ] {
+                let expected = format!(
+                    "Operands must be two numbers or two strings, got {} and {}.",
+                    types.0, types.1
+                );
+                assert_eq!(runtime_error(&source), expected, "{}", source);
+            }
+        }
+    }
+}
+
+#[test]
+fn negate_rejects_non_numbers() {
+    for (bad, ty) in BAD_OPERANDS {
+        let source = format!("-{};", bad);
+        let expected = format!("Operand must be a number, got {}.", ty);
+        assert_eq!(runtime_error(&source), expected, "{}", source);
+    }
+}
+
+#[test]
+fn error_stops_execution() {
+    let mut vm = VM::new();
+    let err = vm.interpret("var x = 1;\nx = \"a\" - 1;\nx = 2;");
+    assert!(err.is_err());
+    assert_eq!(f64::try_from(vm.get_global("x").unwrap()), Ok(1.0));
+}
+
+#[test]
+fn vm_is_reusable_after_error() {
+    let mut vm = VM::new();
+    let source = "fun inner() { return nil * 2; }\nfun outer() { var a = 1; return inner() + a; }\nouter();";
+    assert!(vm.interpret(source).is_err());
+
+    vm.interpret("var y = 1 + 2;").unwrap();
+    assert_eq!(f64::try_from(vm.get_global("y").unwrap()), Ok(3.0));
+}
+
+#[test]
+fn undefined_variable_names_the_variable() {
+    assert_eq!(runtime_error("print missing;"), "Undefined variable 'missing'.");
+    assert_eq!(runtime_error("missing = 1;"), "Undefined variable 'missing'.");
+}
+
+#[test]
+fn trace_lists_every_frame() {
+    let mut vm = VM::new();
+    let source = "fun inner() {\n  return nil * 2;\n}\nfun outer() {\n  inner();\n}\nouter();";
+    let err = vm.interpret(source).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Operands must be numbers, got nil and number.\n\
+         [line 2] in inner()\n\
+         [line 5] in outer()\n\
+         [line 7] in script"
+    );
+}
@@ -0,0 +1,28 @@
+use rslox::VM;
+
+fn errors(source: &str) -> String {
+    let (result, _, err) = VM::new().interpret_capturing(source);
+    assert!(result.is_err(), "{}", source);
+    err
+}
+
+fn output(source: &str) -> String {
+    let (result, out, err) = VM::new().interpret_capturing(source);
+    assert!(result.is_ok(), "{}: {}", source, err);
+    out
+}
+
+#[test]
+fn numbers_end_at_the_end_of_input() {
+    assert_eq!(errors("print 1"), "[line 1] Error at end: Expect ';' after value.\n");
+    assert_eq!(errors("print 2.5"), "[line 1] Error at end: Expect ';' after value.\n");
+    assert_eq!(errors("1"), "[line 1] Error at end: Expect ';' after expression.\n");
+}
+
+#[test]
+fn comments_run_to_the_end_of_the_line() {
+    assert_eq!(output("print 1; // one\nprint 2;"), "1\n2\n");
+    assert_eq!(output("// one\n// two\n  print 3;"), "3\n");
+    assert_eq!(output("print 4; // no newline"), "4\n");
+    assert_eq!(output("print 8 / 2;"), "4\n");
+}
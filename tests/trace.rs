@@ -0,0 +1,73 @@
+#![cfg(feature = "trace")]
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use rslox::{Tracer, VM};
+
+/// A sink the test can read back after handing it to the VM.
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+const PROGRAM: &str = "fun add(a, b) {\n  return a + b;\n}\nvar sum = add(1, 2);\n";
+
+fn trace(tracer: impl FnOnce(Tracer) -> Tracer) -> String {
+    let out = Shared::default();
+    let mut vm = VM::new();
+    vm.set_tracer(Some(tracer(Tracer::new(out.clone()))));
+    vm.interpret(PROGRAM).unwrap();
+    out.text()
+}
+
+#[test]
+fn traces_stack_and_instructions() {
+    let text = trace(|t| t);
+    assert!(text.contains("OP_DEFINE_GLOBAL"));
+    assert!(text.contains("          [ <script> ][ <fn add> ][ 1 ][ 2 ]\n0000    2 OP_GET_LOCAL"));
+}
+
+#[test]
+fn filters_by_function() {
+    let text = trace(|t| t.function("add"));
+    let ops: Vec<_> = text.lines().filter(|line| !line.starts_with(' ')).collect();
+    assert_eq!(ops.len(), 4, "{}", text);
+    assert!(ops[0].ends_with("OP_GET_LOCAL        1"));
+    assert!(ops[3].ends_with("OP_RETURN"));
+
+    assert!(trace(|t| t.function("<script>")).contains("OP_DEFINE_GLOBAL"));
+    assert!(!trace(|t| t.function("<script>")).contains("OP_ADD"));
+}
+
+#[test]
+fn filters_by_line_range() {
+    let text = trace(|t| t.lines(2..=2));
+    assert!(text.contains("OP_ADD"));
+    assert!(!text.contains("OP_DEFINE_GLOBAL"));
+}
+
+#[test]
+fn tracing_can_be_switched_off() {
+    let out = Shared::default();
+    let mut vm = VM::new();
+    assert!(vm.set_tracer(Some(Tracer::new(out.clone()))).is_none());
+    assert!(vm.set_tracer(None).is_some());
+    vm.interpret(PROGRAM).unwrap();
+    assert_eq!(out.text(), "");
+}
@@ -0,0 +1,129 @@
+use rslox::{Value, ValueKind, VM};
+
+#[test]
+fn values_round_trip_through_kind() {
+    for n in [0.0, -0.0, 1.5, -3.25, f64::INFINITY, f64::NEG_INFINITY, f64::MAX, f64::MIN_POSITIVE] {
+        let kind = Value::number(n).kind();
+        assert_eq!(kind, ValueKind::Number(n), "{}", n);
+        assert_eq!(Value::number(n).as_number().unwrap().to_bits(), n.to_bits(), "{}", n);
+    }
+    assert!(Value::number(f64::NAN).as_number().unwrap().is_nan());
+
+    assert_eq!(Value::bool(true).kind(), ValueKind::Bool(true));
+    assert_eq!(Value::bool(false).kind(), ValueKind::Bool(false));
+    assert!(Value::NIL.is_nil());
+    assert!(!Value::number(0.0).is_nil());
+    assert_eq!(Value::number(1.0).as_bool(), None);
+}
+
+#[test]
+fn nan_results_stay_numbers() {
+    let mut vm = VM::new();
+    vm.interpret("var nan = 0 / 0; var negated = -nan; var same = nan == nan;").unwrap();
+
+    assert!(vm.get_global("nan").and_then(Value::as_number).unwrap().is_nan());
+    assert!(vm.get_global("negated").and_then(Value::as_number).unwrap().is_nan());
+    assert_eq!(vm.get_global("same").and_then(Value::as_bool), Some(false));
+}
+
+#[test]
+fn object_handles_survive_encoding() {
+    let mut vm = VM::new();
+    vm.interpret("var s = \"text\"; var t = s;").unwrap();
+
+    let s = vm.get_global("s").unwrap();
+    let r = s.as_obj().unwrap();
+    assert_eq!(s.kind(), ValueKind::Obj(r));
+    assert_eq!(s.display(vm.heap()).to_string(), "text");
+    assert_eq!(vm.get_global("t").and_then(Value::as_obj), Some(r));
+}
+
+#[test]
+fn values_display_as_lox_prints_them() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "var int = 3; var frac = 2.5; var neg = -0.25; var big = 100000000;
+         var str = \"say hi\"; var t = true; var n = nil;
+         fun f() {} class A { m() {} }
+         var inst = A(); var method = inst.m; var native = clock;",
+    )
+    .unwrap();
+
+    let shown = |name: &str| vm.get_global(name).unwrap().display(vm.heap()).to_string();
+    assert_eq!(shown("int"), "3");
+    assert_eq!(shown("frac"), "2.5");
+    assert_eq!(shown("neg"), "-0.25");
+    assert_eq!(shown("big"), "1e+08");
+    assert_eq!(shown("str"), "say hi");
+    assert_eq!(shown("t"), "true");
+    assert_eq!(shown("n"), "nil");
+    assert_eq!(shown("f"), "<fn f>");
+    assert_eq!(shown("A"), "A");
+    assert_eq!(shown("inst"), "A instance");
+    assert_eq!(shown("method"), "<fn m>");
+    assert_eq!(shown("native"), "<native fn>");
+}
+
+#[test]
+fn numbers_print_like_printf_g() {
+    let vm = VM::new();
+    let shown = |n: f64| Value::number(n).display(vm.heap()).to_string();
+    let cases = [
+        (123456.0, "123456"),
+        (1234567.0, "1.23457e+06"),
+        (123456789.0, "1.23457e+08"),
+        (999999.5, "1e+06"),
+        (1e21, "1e+21"),
+        (0.1 + 0.2, "0.3"),
+        (1.0 / 3.0, "0.333333"),
+        (0.0001, "0.0001"),
+        (9.9999996e-5, "0.0001"),
+        (0.00001, "1e-05"),
+        (-1.5e-7, "-1.5e-07"),
+        (-0.0, "-0"),
+        (f64::NAN, "nan"),
+        (f64::INFINITY, "inf"),
+        (f64::NEG_INFINITY, "-inf"),
+    ];
+    for (n, expected) in cases {
+        assert_eq!(shown(n), expected, "{:?}", n);
+    }
+}
+
+#[test]
+fn repr_quotes_strings_only() {
+    let mut vm = VM::new();
+    vm.interpret("var s = \"a\nb\"; var one = 1; var one_str = \"1\";").unwrap();
+
+    let repr = |name: &str| vm.get_global(name).unwrap().repr(vm.heap()).to_string();
+    assert_eq!(repr("s"), "\"a\\nb\"");
+    assert_eq!(repr("one"), "1");
+    assert_eq!(repr("one_str"), "\"1\"");
+}
+
+#[test]
+fn natives_create_and_read_strings() {
+    fn greet(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+        let name = vm.as_str(args[0]).ok_or("Expected a string.")?.to_string();
+        // Both strings must survive the collections triggered in between.
+        let greeting = vm.string(&format!("Hello, {}!", name));
+        let shout = vm.string(&format!("HELLO, {}!", name.to_uppercase()));
+        vm.set_global("shouted", shout);
+        Ok(greeting)
+    }
+
+    let mut vm = VM::new();
+    vm.set_gc_stress(true);
+    vm.define_native("greet", 1, greet);
+    let (result, out, _) = vm.interpret_capturing("print greet(\"lox\"); print shouted; greet(1);");
+    assert_eq!(result.unwrap_err().diagnostics[0].message, "Expected a string.");
+    assert_eq!(out, "Hello, lox!\nHELLO, LOX!\n");
+
+    let host = vm.string("from host");
+    vm.set_global("host", host);
+    vm.collect_garbage();
+    let host = vm.get_global("host").unwrap();
+    assert_eq!(vm.as_str(host), Some("from host"));
+    assert_eq!(vm.as_str(Value::number(1.0)), None);
+    assert_eq!(vm.as_str(vm.get_global("greet").unwrap()), None);
+}
@@ -0,0 +1,213 @@
+mod common;
+
+use rslox::function::Function;
+use rslox::heap::Obj;
+use rslox::verify::verify;
+use rslox::{ErrorKind, Heap, OpCode, Parser, Value, VerifyErrorKind, VM};
+
+fn function(code: &[u8], constants: &[Value]) -> Function {
+    let mut function = Function::new();
+    for &byte in code {
+        function.chunk.write_u8(byte, 1);
+    }
+    function.chunk.constants.extend_from_slice(constants);
+    function
+}
+
+fn check(code: &[u8], constants: &[Value]) -> Result<(), VerifyErrorKind> {
+    let heap = Heap::new();
+    verify(&function(code, constants), &heap, 1).map_err(|err| err.kind)
+}
+
+fn op(code: OpCode) -> u8 {
+    code.into()
+}
+
+/// Verifies `function` and every function nested in its constants.
+fn verify_all(function: &Function, vm: &VM) {
+    let globals = (0..).take_while(|&slot| vm.global_name(slot).is_some()).count();
+    verify(function, vm.heap(), globals).unwrap();
+    for constant in &function.chunk.constants {
+        if let Some(Obj::Function(nested)) = constant.as_obj().map(|r| vm.heap().get(r)) {
+            verify_all(nested, vm);
+        }
+    }
+}
+
+#[test]
+fn accepts_compiler_output() {
+    let mut vm = VM::new();
+    let source = "
+        class Base { init(x) { this.x = x; } get() { return this.x; } }
+        class Derived < Base { get() { return super.get() * 2; } }
+        fun counter() {
+          var n = 0;
+          fun next() { n = n + 1; return n; }
+          return next;
+        }
+        var c = counter();
+        for (var i = 0; i < 10; i = i + 1) {
+          if (i > 5 and !(i == 7) or false) c(); else { var skip = i; }
+        }
+        while (false) {}
+        print Derived(c()).get();
+    ";
+    let script = Parser::new(source, &mut vm).compile().unwrap();
+    verify_all(&script, &vm);
+}
+
+#[test]
+fn rejects_unknown_opcodes() {
+    assert_eq!(check(&[255], &[]), Err(VerifyErrorKind::InvalidOpcode(255)));
+}
+
+#[test]
+fn rejects_truncated_operands() {
+    assert_eq!(check(&[op(OpCode::OpConstantLong), 0, 0], &[]), Err(VerifyErrorKind::Truncated));
+}
+
+#[test]
+fn rejects_bad_constant_indices() {
+    let code = [op(OpCode::OpConstant), 1, op(OpCode::OpReturn)];
+    assert_eq!(check(&code, &[Value::number(1.0)]), Err(VerifyErrorKind::ConstantOutOfRange(1)));
+    assert_eq!(check(&code, &[Value::NIL, Value::NIL]), Ok(()));
+}
+
+#[test]
+fn rejects_name_constants_that_are_not_strings() {
+    let code = [op(OpCode::OpClass), 0, op(OpCode::OpReturn)];
+    assert_eq!(
+        check(&code, &[Value::number(1.0)]),
+        Err(VerifyErrorKind::WrongConstant { index: 0, expected: "string" })
+    );
+
+    let mut heap = Heap::new();
+    let name = heap.intern("Point");
+    let function = function(&code, &[Value::obj(name)]);
+    assert_eq!(verify(&function, &heap, 0), Ok(()));
+}
+
+#[test]
+fn rejects_locals_beyond_the_stack() {
+    // Slot zero is the callee; a unary function also has slot one.
+    let code = [op(OpCode::OpGetLocal), 1, op(OpCode::OpReturn)];
+    assert_eq!(check(&code, &[]), Err(VerifyErrorKind::LocalOutOfRange(1)));
+
+    let heap = Heap::new();
+    let mut unary = function(&code, &[]);
+    unary.arity = 1;
+    assert_eq!(verify(&unary, &heap, 0), Ok(()));
+}
+
+#[test]
+fn rejects_unknown_globals_and_upvalues() {
+    let code = [op(OpCode::OpGetGlobal), 1, op(OpCode::OpReturn)];
+    assert_eq!(check(&code, &[]), Err(VerifyErrorKind::GlobalOutOfRange(1)));
+
+    let code = [op(OpCode::OpGetUpvalue), 0, op(OpCode::OpReturn)];
+    assert_eq!(check(&code, &[]), Err(VerifyErrorKind::UpvalueOutOfRange(0)));
+}
+
+#[test]
+fn rejects_jumps_into_operands() {
+    let code = [
+        op(OpCode::OpJump), 0, 1,
+        op(OpCode::OpConstant), 0,
+        op(OpCode::OpReturn),
+    ];
+    assert_eq!(check(&code, &[Value::NIL]), Err(VerifyErrorKind::JumpIntoInstruction(4)));
+
+    let code = [op(OpCode::OpJump), 0, 9, op(OpCode::OpNil), op(OpCode::OpReturn)];
+    assert_eq!(check(&code, &[]), Err(VerifyErrorKind::JumpOutOfBounds));
+
+    let code = [op(OpCode::OpLoop), 0, 4, op(OpCode::OpReturn)];
+    assert_eq!(check(&code, &[]), Err(VerifyErrorKind::JumpOutOfBounds));
+}
+
+#[test]
+fn rejects_stack_underflow() {
+    let code = [op(OpCode::OpAdd), op(OpCode::OpReturn)];
+    assert_eq!(check(&code, &[]), Err(VerifyErrorKind::StackUnderflow));
+}
+
+#[test]
+fn rejects_inconsistent_stack_depths() {
+    // One branch pushes an extra value before the paths join.
+    let code = [
+        op(OpCode::OpTrue),
+        op(OpCode::OpJumpIfFalse), 0, 1,
+        op(OpCode::OpNil),
+        op(OpCode::OpReturn),
+    ];
+    assert_eq!(
+        check(&code, &[]),
+        Err(VerifyErrorKind::StackMismatch { expected: 2, found: 3 })
+    );
+}
+
+#[test]
+fn rejects_code_that_runs_off_the_end() {
+    assert_eq!(check(&[], &[]), Err(VerifyErrorKind::FallsOffEnd));
+    assert_eq!(check(&[op(OpCode::OpNil)], &[]), Err(VerifyErrorKind::FallsOffEnd));
+}
+
+#[test]
+fn reports_the_offending_function_and_offset() {
+    let heap = Heap::new();
+    let code = [op(OpCode::OpNil), op(OpCode::OpPop), op(OpCode::OpPop), op(OpCode::OpPop)];
+    let mut bad = function(&code, &[]);
+    bad.name = "bad".to_string();
+    let err = verify(&bad, &heap, 0).unwrap_err();
+    assert_eq!((err.function.as_str(), err.offset), ("bad", 3));
+    assert_eq!(err.to_string(), "bad at offset 3: stack underflow.");
+}
+
+/// Loads `code` as a `.loxc` script with string `constants`, which must
+/// pass verification, and returns the runtime error it stops with.
+fn runtime_error(code: &[u8], constants: &[&str]) -> String {
+    let err = VM::new().interpret_bytecode(&common::script(code, constants)).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Runtime, "{}", err);
+    err.diagnostics[0].message.clone()
+}
+
+#[test]
+fn ill_typed_class_operands_are_runtime_errors() {
+    let method = [
+        op(OpCode::OpNil),
+        op(OpCode::OpNil),
+        op(OpCode::OpMethod),
+        0,
+        op(OpCode::OpNil),
+        op(OpCode::OpReturn),
+    ];
+    assert_eq!(runtime_error(&method, &["m"]), "Method body must be a closure.");
+
+    let get_super = [
+        op(OpCode::OpNil),
+        op(OpCode::OpConstant),
+        0,
+        op(OpCode::OpGetSuper),
+        0,
+        op(OpCode::OpReturn),
+    ];
+    assert_eq!(runtime_error(&get_super, &["m"]), "Superclass must be a class.");
+
+    let inherit_from_nil = [
+        op(OpCode::OpClass),
+        0,
+        op(OpCode::OpNil),
+        op(OpCode::OpInherit),
+        op(OpCode::OpReturn),
+    ];
+    assert_eq!(runtime_error(&inherit_from_nil, &["A"]), "Only classes can inherit.");
+
+    let inherit_from_string = [
+        op(OpCode::OpClass),
+        0,
+        op(OpCode::OpConstant),
+        0,
+        op(OpCode::OpInherit),
+        op(OpCode::OpReturn),
+    ];
+    assert_eq!(runtime_error(&inherit_from_string, &["A"]), "Only classes can inherit.");
+}